@@ -0,0 +1,112 @@
+//! Times method-heavy Horst programs. Run with `cargo bench --bench method_calls`.
+
+use std::time::{Duration, Instant};
+use horst::{
+    scanner::Scanner,
+    parser::Parser,
+    compiler::Compiler,
+    vm::VM,
+};
+
+const RUNS: usize = 10;
+
+/// Calls the same method on the same class over and over, the best case for a call site.
+const MONOMORPHIC: &str = "
+    class Counter {
+        init() { this.count = 0; }
+        increment(by) { this.count = this.count + by; return this; }
+        value() { return this.count; }
+    }
+    let counter = Counter();
+    let i = 0;
+    while (i < 100000) {
+        counter.increment(1).increment(2);
+        i = i + 1;
+    }
+    return counter.value();
+";
+
+/// Does the same calls as `MONOMORPHIC`, but reads each method into a local first and calls
+/// the bound method, bypassing the inline cache. The baseline the cached case is measured against.
+const UNCACHED: &str = "
+    class Counter {
+        init() { this.count = 0; }
+        increment(by) { this.count = this.count + by; return this; }
+        value() { return this.count; }
+    }
+    let counter = Counter();
+    let i = 0;
+    while (i < 100000) {
+        let increment = counter.increment;
+        increment(1);
+        increment = counter.increment;
+        increment(2);
+        i = i + 1;
+    }
+    let value = counter.value;
+    return value();
+";
+
+/// Alternates between two classes at one call site, missing the cache on every call.
+const POLYMORPHIC: &str = "
+    class Square {
+        init(side) { this.side = side; }
+        area() { return this.side * this.side; }
+    }
+    class Rectangle {
+        init(width, height) { this.width = width; this.height = height; }
+        area() { return this.width * this.height; }
+    }
+    let shapes = [Square(2), Rectangle(2, 3)];
+    let total = 0;
+    let i = 0;
+    while (i < 100000) {
+        total = total + shapes[i % 2].area();
+        i = i + 1;
+    }
+    return total;
+";
+
+/// Passes strings around and reads the fields holding them.
+const STRINGS: &str = "
+    class Person {
+        init(name) { this.name = name; this.greeting = \"Hello\"; }
+    }
+    let people = [Person(\"Ada\"), Person(\"Grace\")];
+    let count = 0;
+    let i = 0;
+    while (i < 100000) {
+        let person = people[i % 2];
+        let name = person.name;
+        let greeting = person.greeting;
+        if (name == \"Ada\" and greeting == \"Hello\") {
+            count = count + 1;
+        }
+        i = i + 1;
+    }
+    return count;
+";
+
+fn main() {
+    let programs = [
+        ("monomorphic", MONOMORPHIC),
+        ("uncached", UNCACHED),
+        ("polymorphic", POLYMORPHIC),
+        ("strings", STRINGS),
+    ];
+    for (name, source) in programs {
+        let fastest = (0..RUNS).map(|_| time(source)).min().unwrap();
+        println!("{:<12} {:>8.2} ms (fastest of {} runs)", name, fastest.as_secs_f64() * 1000.0, RUNS);
+    }
+}
+
+fn time(source: &str) -> Duration {
+    let tokens = Scanner::new(source).scan_tokens().unwrap();
+    let statements = Parser::new(tokens).parse().unwrap();
+    let program = Compiler::new().compile(&statements).unwrap();
+    let mut vm = VM::new(program);
+
+    let start = Instant::now();
+    vm.run().unwrap();
+    start.elapsed()
+}
@@ -0,0 +1,202 @@
+use crate::span::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// The location diagnostics about this expression point at: the operator of a unary,
+    /// binary or assignment expression, the argument list of a call, the brackets of an index,
+    /// the property name of a get, the opening bracket or brace of a list or map literal, the
+    /// `fn` or `super` keyword, and the whole token otherwise.
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(f64),
+    String(String),
+    /// The literal segments and interpolated expressions of a `"...${expr}..."` string.
+    Interpolation(Vec<Expr>),
+    Boolean(bool),
+    Nil,
+    Variable(String),
+    List(Vec<Expr>),
+    /// Key and value pairs of a map literal.
+    Map(Vec<(Expr, Expr)>),
+    Assign {
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    /// `name op= value`.
+    CompoundAssign {
+        name: Identifier,
+        op: BinaryOp,
+        value: Box<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        op: BinaryOp,
+        right: Box<Expr>,
+    },
+    Logical {
+        left: Box<Expr>,
+        op: LogicalOp,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    /// `object.name op= value`, evaluating `object` once.
+    CompoundSet {
+        object: Box<Expr>,
+        name: Identifier,
+        op: BinaryOp,
+        value: Box<Expr>,
+    },
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    SetIndex {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    /// `object[index] op= value`, evaluating `object` and `index` once.
+    CompoundSetIndex {
+        object: Box<Expr>,
+        index: Box<Expr>,
+        op: BinaryOp,
+        value: Box<Expr>,
+    },
+    Function(Box<FunctionDecl>),
+    This,
+    Super {
+        method: Identifier,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    /// The keyword starting the statement, the declared name, or the expression's span.
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Let {
+        name: Identifier,
+        initializer: Option<Expr>,
+    },
+    Block(Vec<Stmt>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        label: Option<Identifier>,
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    /// A C-style `for (initializer; condition; increment)` loop.
+    For {
+        label: Option<Identifier>,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    /// `for (item in iterable)`, driven by the iterator protocol: `iterable.iterator()` returns
+    /// an object whose `done` field is checked before each call to its `next()` method.
+    ForIn {
+        label: Option<Identifier>,
+        item: Identifier,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
+    /// `break`, optionally naming the labeled loop to leave.
+    Break(Option<Identifier>),
+    /// `continue`, optionally naming the labeled loop to restart.
+    Continue(Option<Identifier>),
+    Return(Option<Expr>),
+    Function(FunctionDecl),
+    Class(ClassDecl),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDecl {
+    /// `None` for anonymous `fn` expressions.
+    pub name: Option<Identifier>,
+    /// The `///` comment lines preceding the declaration, joined by newlines.
+    pub doc: Option<String>,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+    /// The span of the closing brace of the body.
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDecl {
+    pub name: Identifier,
+    pub doc: Option<String>,
+    pub superclass: Option<Identifier>,
+    pub methods: Vec<FunctionDecl>,
+}
@@ -1,29 +1,62 @@
+use std::io::IsTerminal;
 use horst::{
     scanner::{Scanner},
+    parser::{Parser},
     compiler::{Compiler},
+    diagnostics::{Diagnostic, Format, Renderer},
     vm::{VM},
 };
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} <file>", args[0]);
+    let mut format = if std::io::stderr().is_terminal() { Format::Human } else { Format::Plain };
+    let mut files = vec![];
+
+    for arg in &args[1..] {
+        match arg.strip_prefix("--error-format=") {
+            Some("human") => format = Format::Human,
+            Some("plain") => format = Format::Plain,
+            Some("json") => format = Format::Json,
+            Some(other) => {
+                eprintln!("Unknown error format '{}'. Expected human, plain or json.", other);
+                std::process::exit(2);
+            }
+            None => files.push(arg),
+        }
+    }
+
+    if files.len() != 1 {
+        println!("Usage: {} [--error-format=human|plain|json] <file>", args[0]);
         return;
     }
-    let filename = &args[1];
+    let filename = files[0];
     let contents = std::fs::read_to_string(filename).expect("Something went wrong reading the file");
-
-    let mut scanner = Scanner::new(contents);
-    scanner.scan_tokens();
-    let mut compiler = Compiler::new(scanner.tokens);
-    let program = compiler.compile();
+    let renderer = Renderer::new(&contents, filename, format);
+
+    let tokens = match Scanner::new(&contents).scan_tokens() {
+        Ok(tokens) => tokens,
+        Err(errors) => report(&renderer, errors.iter().map(Diagnostic::from)),
+    };
+    let statements = match Parser::new(tokens).parse() {
+        Ok(statements) => statements,
+        Err(errors) => report(&renderer, errors.iter().map(Diagnostic::from)),
+    };
+    let program = match Compiler::new().compile(&statements) {
+        Ok(program) => program,
+        Err(errors) => report(&renderer, errors.iter().map(Diagnostic::from)),
+    };
 
     let mut vm = VM::new(program);
-    let result = vm.run();
-
-    println!("Program exited with {}", result);
-
-
+    match vm.run() {
+        Ok(result) => println!("Program exited with {}", result.to_string(&vm)),
+        Err(error) => report(&renderer, std::iter::once(Diagnostic::from(&error))),
+    }
+}
 
-}
\ No newline at end of file
+fn report(renderer: &Renderer, diagnostics: impl Iterator<Item = Diagnostic>) -> ! {
+    for diagnostic in diagnostics {
+        eprint!("{}", renderer.render(&diagnostic));
+    }
+    std::process::exit(1);
+}
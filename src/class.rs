@@ -1,18 +1,46 @@
+use std::any::Any;
 use std::collections::HashMap;
-use crate::function::Function;
+use std::rc::Rc;
+use crate::function::{Closure, Function};
+use crate::symbol::Symbol;
 use crate::value::Value;
+use crate::vm::{Collectable, VM};
 
+/// A class on the VM heap, shared by its instances and referred to by `Value::Class` ids.
 #[derive(Clone, PartialEq, Debug)]
 pub struct Class {
     pub name: String,
-    pub methods: HashMap<String, Value>,
+    pub methods: HashMap<Symbol, Value>,
+    pub doc: Option<String>,
 }
 
 impl Class {
-    pub fn new(name: String, methods: HashMap<String, Function>) -> Class {
+    pub fn new(name: String, methods: HashMap<Symbol, Function>) -> Class {
         Class {
             name,
-            methods: methods.into_iter().map(|(name, function)| (name, Value::Function(function))).collect()
+            methods: methods
+                .into_iter()
+                .map(|(name, function)| (name, Value::Closure(Rc::new(Closure::new(Rc::new(function))))))
+                .collect(),
+            doc: None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Collectable for Class {
+    fn collect(&self) -> Vec<usize> {
+        self.methods.values().flat_map(Value::collect).collect()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn to_string(&self, _: &VM) -> Option<String> {
+        Some(format!("class {}", self.name))
+    }
+}
@@ -1,666 +1,882 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use crate::ast::{BinaryOp, ClassDecl, Expr, ExprKind, FunctionDecl, Identifier, LogicalOp, Stmt, StmtKind, UnaryOp};
 use crate::class::Class;
-use crate::function::Function;
+use crate::function::{Capture, Function};
 use crate::instruction::Instruction;
-use crate::native_functions::{NATIVE_FUNCTIONS, NATIVE_CLASSES};
-use crate::token::Token;
+use crate::native_functions::{NATIVE_FUNCTIONS, NATIVE_CLASS_NAMES};
+use crate::span::Span;
+use crate::symbol::{Interner, Symbol};
+use crate::token::{Token, TokenKind};
 use crate::value::Value;
 
 #[derive(PartialEq)]
 enum FunctionKind {
     Function,
     Method,
-    Anonymous,
 }
 
+type Code = Vec<(Instruction, Span)>;
+
+type CompileResult<T> = Result<T, CompileError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    /// The token the error was detected at.
+    pub token: Token,
+    /// A description of what the compiler expected to find instead, if there is one.
+    pub expected: Option<String>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.token.kind == TokenKind::Eof {
+            write!(f, "[{}] Error at end: {}", self.token.span, self.message)
+        } else {
+            write!(f, "[{}] Error at '{}': {}", self.token.span, self.token.kind, self.message)
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
+    pub spans: Vec<Span>,
     pub constants: Vec<Value>,
+    /// The class declarations, allocated on the heap by `Class` instructions.
+    pub classes: Vec<Class>,
+    /// The property, method and native class names instructions refer to by index.
+    pub names: Vec<Symbol>,
+    /// The table `names` and the string constants were interned in.
+    pub strings: Interner,
     pub global_count: usize,
+    /// Global variable names, indexed by slot, for error messages.
+    pub global_names: Vec<String>,
+    /// The number of `Invoke` call sites, each with its own inline cache slot.
+    pub inline_cache_count: usize,
 }
 
+/// Generates bytecode from the statements produced by the parser.
 pub struct Compiler {
-    tokens: Vec<Token>,
-    current: usize,
+    /// Every function being compiled, innermost last.
+    functions: Vec<FunctionState>,
     constants: Vec<Value>,
+    classes: Vec<Class>,
+    names: Vec<Symbol>,
+    strings: Interner,
     globals: HashMap<String, usize>,
     scopes: Vec<Scope>,
-    in_function: bool,
-    current_super: Option<Instruction>,
-    upvalue_count: usize,
+    /// Whether the class whose methods are being compiled has a superclass.
+    current_super: bool,
+    inline_cache_count: usize,
+    errors: Vec<CompileError>,
+}
+
+struct FunctionState {
+    code: Code,
+    /// The index of the function's outermost scope. Locals of scopes below it belong to
+    /// enclosing functions and are only reachable as upvalues.
+    scope_base: usize,
+    /// The loops enclosing the code being compiled, innermost last.
+    loops: Vec<Loop>,
+    /// The variables of enclosing functions the function captures, by upvalue index.
+    upvalues: Vec<Capture>,
+}
+
+impl FunctionState {
+    fn new(scope_base: usize) -> FunctionState {
+        FunctionState {
+            code: vec![],
+            scope_base,
+            loops: vec![],
+            upvalues: vec![],
+        }
+    }
+}
+
+struct Loop {
+    label: Option<String>,
+    /// The instruction `continue` jumps back to, or `None` if it jumps forward to an increment
+    /// that hasn't been compiled yet.
+    continue_target: Option<usize>,
+    /// The number of locals that live outside the loop body. `break` and `continue` pop the
+    /// locals above it.
+    locals: usize,
+    /// `break` jumps to patch once the end of the loop is known.
+    breaks: Vec<usize>,
+    /// Forward `continue` jumps to patch once the increment is reached.
+    continues: Vec<usize>,
+}
+
+impl Loop {
+    fn new(label: &Option<Identifier>, continue_target: Option<usize>, locals: usize) -> Loop {
+        Loop {
+            label: label.as_ref().map(|label| label.name.clone()),
+            continue_target,
+            locals,
+            breaks: vec![],
+            continues: vec![],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 struct Scope {
-    locals: HashMap<String, usize>,
-    upvalues: HashMap<String, Upvalue>,
+    /// The scope's locals in declaration order.
+    locals: Vec<Local>,
 }
 
 impl Scope {
     pub fn new() -> Scope {
         Self {
-            locals: HashMap::new(),
-            upvalues: HashMap::new(),
+            locals: vec![],
         }
     }
+
+    fn local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().find(|local| local.name == name).map(|local| local.slot)
+    }
 }
 
 #[derive(Debug, Clone)]
-struct Upvalue {
-    is_local: bool,
-    local_index: usize,
-    upvalue_index: usize,
+struct Local {
+    name: String,
+    /// The stack slot of the local, relative to the frame's base pointer.
+    slot: usize,
+    /// Whether a closure captures the local, so its upvalue has to be closed when it's popped.
+    captured: bool,
+    /// The `Pop` instructions that `break` and `continue` emitted for the local. They are
+    /// turned into `CloseUpvalue` if a closure later in the loop body captures it.
+    exits: Vec<usize>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
-    pub fn new(tokens: Vec<Token>) -> Compiler {
+    pub fn new() -> Compiler {
         Compiler {
-            tokens,
-            current: 0,
+            functions: vec![FunctionState::new(0)],
             constants: vec![],
+            classes: vec![],
+            names: vec![],
+            strings: Interner::default(),
             globals: HashMap::new(),
             scopes: vec![Scope::new()],
-            in_function: false,
-            current_super: None,
-            upvalue_count: 0,
+            current_super: false,
+            inline_cache_count: 0,
+            errors: vec![],
         }
     }
 
-    pub fn compile(&mut self) -> Program {
-        let mut instructions = vec![];
+    /// Compiles a parsed script. Like the parser, the compiler reports every error instead of
+    /// stopping at the first one.
+    pub fn compile(&mut self, statements: &[Stmt]) -> Result<Program, Vec<CompileError>> {
+        for statement in statements {
+            self.declaration(statement);
+        }
 
-        while !self.is_at_end() {
-            instructions.extend(self.declaration());
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
         }
 
-        instructions.push(Instruction::Halt);
+        let end = statements.last().map(|statement| statement.span).unwrap_or_default();
+        self.emit(Instruction::Halt, end);
 
-        Program {
+        let (instructions, spans) = std::mem::take(self.current_code()).into_iter().unzip();
+        Ok(Program {
             instructions,
+            spans,
             constants: self.constants.clone(),
+            classes: self.classes.clone(),
+            names: self.names.clone(),
+            strings: self.strings.clone(),
             global_count: self.global_count(),
-        }
-    }
-
-    fn declaration(&mut self) -> Vec<Instruction> {
-        if self.match_token(Token::Let) {
-            self.let_declaration()
-        } else if self.match_token(Token::Fn) {
-            self.function_declaration()
-        } else if self.match_token(Token::Class) {
-            self.class_declaration()
-        } else {
-            self.statement()
-        }
-    }
-
-    fn let_declaration(&mut self) -> Vec<Instruction> {
-        let name = self.consume_identifier("Expect variable name.");
-        let global = self.scopes.len() == 1;
-
-        let initializer = if self.match_token(Token::Equal) {
-            self.expression()
-        } else {
-            vec![Instruction::Nil]
-        };
-
-        self.match_token(Token::Semicolon);
-
-        if global {
-            self.define_global(name, initializer)
-        } else {
-            self.define_local(name, initializer)
-        }
+            global_names: self.global_names(),
+            inline_cache_count: self.inline_cache_count,
+        })
     }
 
-    fn define_global(&mut self, name: String, mut initializer: Vec<Instruction>) -> Vec<Instruction> {
-        let mut index = self.global_count();
-        if self.globals.contains_key(&name) {
-            index = self.globals[&name];
+    /// Compiles a statement, recording its error and discarding its partial code on failure.
+    fn declaration(&mut self, statement: &Stmt) {
+        let function_depth = self.functions.len();
+        let code_length = self.current_code().len();
+        let loop_depth = self.current_function().loops.len();
+        let scope_depth = self.scopes.len();
+        let current_super = self.current_super;
+
+        if let Err(error) = self.statement(statement) {
+            self.errors.push(error);
+            self.functions.truncate(function_depth);
+            let function = self.current_function();
+            function.code.truncate(code_length);
+            function.loops.truncate(loop_depth);
+            for enclosing in &mut function.loops {
+                enclosing.breaks.retain(|&jump| jump < code_length);
+                enclosing.continues.retain(|&jump| jump < code_length);
+            }
+            self.scopes.truncate(scope_depth);
+            let scope_base = self.current_function().scope_base;
+            for scope in &mut self.scopes[scope_base..] {
+                for local in &mut scope.locals {
+                    local.exits.retain(|&pop| pop < code_length);
+                }
+            }
+            self.current_super = current_super;
         }
-        self.globals.insert(name, index);
-
-        initializer.push(Instruction::DefineGlobal(index));
-        initializer
     }
 
-    fn define_local(&mut self, name: String, initializer: Vec<Instruction>) -> Vec<Instruction> {
-        assert!(!self.current_scope().locals.contains_key(&name), "Variable with this name already defined in the same scope: {}", name);
-
-        let index = self.local_count();
-        self.current_scope_mut().locals.insert(name, index);
-        initializer
-    }
-
-    fn function_declaration(&mut self) -> Vec<Instruction> {
-        let name = self.consume_identifier("Expect function name.");
-        let function = self.function(FunctionKind::Function);
-        self.define_global(name, function)
-    }
-
-    fn function(&mut self, kind: FunctionKind) -> Vec<Instruction> {
-        self.in_function = true;
-        self.begin_scope();
-        self.consume_token(Token::LeftParen, "Expect '(' after function name.");
-
-        let mut parameters = vec![];
-        if kind == FunctionKind::Method {
-            self.define_local("this".to_string(), vec![]);
-        }
-
-        if !self.check(&Token::RightParen) {
-            loop {
-                let param = self.consume_identifier("Expect parameter name.");
-
-                assert!(!self.current_scope().locals.contains_key(&param), "Cannot have two parameters with the same name: {}", param);
+    fn statement(&mut self, statement: &Stmt) -> CompileResult<()> {
+        let span = statement.span;
 
-                self.define_local(param.clone(), vec![]);
+        match &statement.kind {
+            StmtKind::Expression(expression) => {
+                self.expression(expression)?;
+                self.emit(Instruction::Pop, span);
+            }
+            StmtKind::Print(expression) => {
+                self.expression(expression)?;
+                self.emit(Instruction::Print, span);
+            }
+            StmtKind::Let { name, initializer } => {
+                let global = self.scopes.len() == 1;
 
-                parameters.push(param);
+                match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => {
+                        self.emit(Instruction::Nil, name.span);
+                    }
+                }
 
-                if !self.match_token(Token::Comma) {
-                    break;
+                if global {
+                    self.define_global(&name.name, name.span);
+                } else {
+                    self.define_local(&name.name, name.span)?;
                 }
             }
-        }
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.declaration(statement);
+                }
+                self.end_scope(span);
+            }
+            StmtKind::If { condition, then_branch, else_branch } => {
+                self.expression(condition)?;
+                let then_jump = self.emit(Instruction::JumpIfFalse(0), span);
+                self.statement(then_branch)?;
+                let else_jump = self.emit(Instruction::Jump(0), span);
+                self.patch_jump(then_jump);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch)?;
+                }
+                self.patch_jump(else_jump);
+            }
+            StmtKind::While { label, condition, body } => {
+                let loop_start = self.current_code().len();
+                self.expression(condition)?;
+                let exit_jump = self.emit(Instruction::JumpIfFalse(0), span);
+
+                let locals = self.local_total();
+                self.current_function().loops.push(Loop::new(label, Some(loop_start), locals));
+                self.statement(body)?;
+                self.emit_loop(loop_start, span);
+                self.patch_jump(exit_jump);
+                self.end_loop();
+            }
+            StmtKind::For { label, initializer, condition, increment, body } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer)?;
+                }
 
-        self.consume_token(Token::RightParen, "Expect ')' after parameters.");
+                let loop_start = self.current_code().len();
+                let exit_jump = match condition {
+                    Some(condition) => {
+                        self.expression(condition)?;
+                        Some(self.emit(Instruction::JumpIfFalse(0), span))
+                    }
+                    None => None,
+                };
 
-        let mut body = self.block();
+                let locals = self.local_total();
+                self.current_function().loops.push(Loop::new(label, None, locals));
+                self.statement(body)?;
 
-        let mut upvalues = vec![];
-        for (key, upvalue) in self.current_scope().upvalues.iter() {
-            if !upvalue.is_local && self.scopes[self.scopes.len() - 2].upvalues[key].is_local {
-                upvalues.push(Instruction::MakeUpvalue(upvalue.upvalue_index, upvalue.local_index));
+                let continues = std::mem::take(&mut self.current_function().loops.last_mut().unwrap().continues);
+                for jump in continues {
+                    self.patch_jump(jump);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment)?;
+                    self.emit(Instruction::Pop, span);
+                }
+                self.emit_loop(loop_start, span);
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump);
+                }
+                self.end_loop();
+                self.end_scope(span);
             }
+            StmtKind::ForIn { label, item, iterable, body } => self.for_in_statement(label, item, iterable, body, span)?,
+            StmtKind::Break(label) => {
+                let index = self.find_loop(label, TokenKind::Break, span)?;
+                self.pop_loop_locals(index, span);
+                let jump = self.emit(Instruction::Jump(0), span);
+                self.current_function().loops[index].breaks.push(jump);
+            }
+            StmtKind::Continue(label) => {
+                let index = self.find_loop(label, TokenKind::Continue, span)?;
+                self.pop_loop_locals(index, span);
+                match self.current_function().loops[index].continue_target {
+                    Some(target) => self.emit_loop(target, span),
+                    None => {
+                        let jump = self.emit(Instruction::Jump(0), span);
+                        self.current_function().loops[index].continues.push(jump);
+                    }
+                }
+            }
+            StmtKind::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => {
+                        self.emit(Instruction::Nil, span);
+                    }
+                }
+                self.emit(Instruction::Return, span);
+            }
+            StmtKind::Function(function) => {
+                if self.scopes.len() == 1 {
+                    self.function(function, FunctionKind::Function)?;
+                    self.define_global(&function_name(function), span);
+                } else {
+                    // The closure is pushed into the local's slot, so declaring the local first
+                    // lets the function capture itself and recurse.
+                    self.define_local(&function_name(function), span)?;
+                    self.function(function, FunctionKind::Function)?;
+                }
+            }
+            StmtKind::Class(class) => self.class_declaration(class, span)?,
         }
 
-        body.extend(self.end_scope());
-        if body.last() != Some(&Instruction::Return) {
-            body.push(Instruction::Nil);
-            body.push(Instruction::Return);
-        }
-
-        let index = self.add_constant(Value::Function(Function::new(
-            body,
-            parameters.len(),
-        )));
-
-        self.in_function = false;
-
-        upvalues.extend(vec![Instruction::Constant(index), Instruction::MakeClosure]);
-        upvalues
-    }
-
-    fn begin_scope(&mut self) {
-        self.scopes.push(Scope::new());
-    }
-
-    fn end_scope(&mut self) -> Vec<Instruction> {
-        let scope = self.scopes.pop().unwrap();
-        scope
-            .locals
-            .into_iter()
-            .map(|_| Instruction::Pop)
-            .collect()
-    }
-
-    fn current_scope(&self) -> &Scope {
-        self.scopes.last().unwrap()
+        Ok(())
     }
 
-    fn current_scope_mut(&mut self) -> &mut Scope {
-        self.scopes.last_mut().unwrap()
-    }
+    /// Compiles `for (item in iterable)` as a loop over `iterable.iterator()`, checking the
+    /// iterator's `done` field before each call to its `next()` method.
+    fn for_in_statement(&mut self, label: &Option<Identifier>, item: &Identifier, iterable: &Expr, body: &Stmt, span: Span) -> CompileResult<()> {
+        let iterator_name = self.add_name("iterator");
+        let done_name = self.add_name("done");
+        let next_name = self.add_name("next");
 
-    fn local_count(&self) -> usize {
-        self.scopes.last().unwrap().locals.len()
+        self.begin_scope();
+        self.expression(iterable)?;
+        self.invoke(iterator_name, 0, span);
+        let iterator = self.define_local("<iterator>", span)?;
+
+        let loop_start = self.current_code().len();
+        self.emit(Instruction::GetLocal(iterator), span);
+        self.emit(Instruction::GetProperty(done_name), span);
+        let exit_jump = self.emit(Instruction::JumpIfTrueOrPop(0), span);
+
+        let locals = self.local_total();
+        self.current_function().loops.push(Loop::new(label, Some(loop_start), locals));
+
+        // The item is pushed anew for every iteration and popped at its end, so it's only a
+        // local of the loop scope while the body is compiled.
+        self.emit(Instruction::GetLocal(iterator), item.span);
+        self.invoke(next_name, 0, item.span);
+        self.define_local(&item.name, item.span)?;
+        self.statement(body)?;
+        let item = self.current_scope_mut().locals.pop().unwrap();
+        self.pop_local(item, span);
+        self.emit_loop(loop_start, span);
+
+        // `done` is left on the stack when the loop exits normally, but not by `break`.
+        self.patch_jump(exit_jump);
+        self.emit(Instruction::Pop, span);
+        self.end_loop();
+        self.end_scope(span);
+        Ok(())
     }
 
-    fn global_count(&self) -> usize {
-        self.globals.len()
+    fn end_loop(&mut self) {
+        let finished = self.current_function().loops.pop().unwrap();
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
     }
 
-    fn add_constant(&mut self, value: Value) -> usize {
-        for (index, constant) in self.constants.iter().enumerate() {
-            if *constant == value {
-                return index;
-            }
+    /// Finds the innermost loop, or the innermost loop with the given label.
+    fn find_loop(&mut self, label: &Option<Identifier>, keyword: TokenKind, span: Span) -> CompileResult<usize> {
+        let loops = &self.current_function().loops;
+
+        match label {
+            None => loops.len().checked_sub(1).ok_or_else(|| {
+                error_at(keyword.clone(), span, &format!("Can't use '{}' outside of a loop.", keyword))
+            }),
+            Some(label) => loops
+                .iter()
+                .rposition(|enclosing| enclosing.label.as_ref() == Some(&label.name))
+                .ok_or_else(|| {
+                    error_at(
+                        TokenKind::Identifier(label.name.clone()),
+                        label.span,
+                        &format!("Undefined loop label '{}'.", label.name),
+                    )
+                }),
         }
-
-        self.constants.push(value);
-        self.constants.len() - 1
     }
 
-    fn class_declaration(&mut self) -> Vec<Instruction> {
-        let name = self.consume_identifier("Expect class name.");
-        let superclass = if self.match_token(Token::Less) {
-            let name = self.consume_identifier("Expect superclass name.");
-            Some(self.get_variable(&name))
-        } else {
-            None
-        };
-        self.current_super = superclass.clone();
-
-        self.consume_token(Token::LeftBrace, "Expect '{' before class body.");
-
-        let mut methods = HashMap::new();
+    /// Pops the locals a jump out of the body of the loop at `index` leaves behind.
+    fn pop_loop_locals(&mut self, index: usize, span: Span) {
+        let count = self.local_total() - self.current_function().loops[index].locals;
+        let scope_base = self.current_function().scope_base;
+        let mut exits = vec![];
+        for _ in 0..count {
+            exits.push(self.emit(Instruction::Pop, span));
+        }
 
-        while !self.check(&Token::RightBrace) && !self.is_at_end() {
-            let name = self.consume_identifier("Expect function name.");
-            let function = self.function(FunctionKind::Method);
-            let function = self.remove_constant(function[0]);
-            if let Value::Function(function) = function {
-                methods.insert(name, function);
+        let locals = self.scopes[scope_base..].iter_mut().rev().flat_map(|scope| scope.locals.iter_mut().rev());
+        for (local, exit) in locals.zip(exits) {
+            if local.captured {
+                self.functions.last_mut().unwrap().code[exit].0 = Instruction::CloseUpvalue;
             } else {
-                panic!("Expected function.");
+                local.exits.push(exit);
             }
         }
-        self.current_super = None;
-
-        self.consume_token(Token::RightBrace, "Expect '}' after class body.");
-
-        let index = self.add_constant(Value::Class(Class::new(name.clone(), methods)));
+    }
 
-        let mut instructions = vec![Instruction::Constant(index)];
-        if let Some(superclass) = superclass {
-            instructions.push(superclass);
-            instructions.push(Instruction::Inherit);
+    fn define_global(&mut self, name: &str, span: Span) {
+        let mut index = self.global_count();
+        if self.globals.contains_key(name) {
+            index = self.globals[name];
         }
+        self.globals.insert(name.to_string(), index);
 
-        self.define_global(name, instructions)
+        self.emit(Instruction::DefineGlobal(index), span);
     }
 
-    fn remove_constant(&mut self, instruction: Instruction) -> Value {
-        match instruction {
-            Instruction::Constant(index) => self.constants.remove(index),
-            _ => panic!("Expected constant instruction."),
+    fn define_local(&mut self, name: &str, span: Span) -> CompileResult<usize> {
+        if self.current_scope().local(name).is_some() {
+            return Err(error_at(
+                TokenKind::Identifier(name.to_string()),
+                span,
+                &format!("Variable with this name already defined in the same scope: {}", name),
+            ));
         }
+
+        let slot = self.local_total();
+        self.current_scope_mut().locals.push(Local {
+            name: name.to_string(),
+            slot,
+            captured: false,
+            exits: vec![],
+        });
+        Ok(slot)
     }
 
-    fn statement(&mut self) -> Vec<Instruction> {
-        if self.match_token(Token::Print) {
-            self.print_statement()
-        } else if self.check(&Token::LeftBrace) {
-            self.block_statement()
-        } else if self.match_token(Token::If) {
-            self.if_statement()
-        } else if self.match_token(Token::While) {
-            self.while_statement()
-        } else if self.match_token(Token::Return) {
-            self.return_statement()
-        } else {
-            self.expression_statement()
-        }
+    /// Compiles a function and emits the instructions creating its closure.
+    fn function(&mut self, declaration: &FunctionDecl, kind: FunctionKind) -> CompileResult<()> {
+        let function = self.function_body(declaration, kind)?;
+        let index = self.add_constant(Value::Function(Rc::new(function)));
+        self.emit(Instruction::Constant(index), declaration.span);
+        self.emit(Instruction::MakeClosure, declaration.span);
+        Ok(())
     }
 
-    fn block_statement(&mut self) -> Vec<Instruction> {
+    /// Compiles a function body. The function's `captures` tell `MakeClosure`, or `Class` for a
+    /// method, which variables of the enclosing function it closes over.
+    fn function_body(&mut self, declaration: &FunctionDecl, kind: FunctionKind) -> CompileResult<Function> {
+        self.functions.push(FunctionState::new(self.scopes.len()));
         self.begin_scope();
-        let mut instructions = self.block();
-        instructions.extend(self.end_scope());
-
-        instructions
-    }
 
-    fn if_statement(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
+        if kind == FunctionKind::Method {
+            self.define_local("this", declaration.span)?;
+        }
 
-        self.consume_token(Token::LeftParen, "Expect '(' after 'if'.");
-        instructions.extend(self.expression());
-        self.consume_token(Token::RightParen, "Expect ')' after condition.");
+        for param in &declaration.params {
+            self.define_local(&param.name, param.span)?;
+        }
 
-        let then_instructions = self.block_statement();
-        let mut else_instructions = vec![];
+        for statement in &declaration.body {
+            self.declaration(statement);
+        }
 
-        if self.match_token(Token::Else) {
-            else_instructions = self.block_statement();
+        self.end_scope(declaration.end);
+        let state = self.functions.pop().unwrap();
+        let mut body = state.code;
+        if body.last().map(|(instruction, _)| instruction) != Some(&Instruction::Return) {
+            body.push((Instruction::Nil, declaration.end));
+            body.push((Instruction::Return, declaration.end));
         }
 
-        instructions.push(Instruction::JumpIfFalse(then_instructions.len() + 2));
-        instructions.extend(then_instructions);
-        instructions.push(Instruction::Jump(else_instructions.len() + 1));
-        instructions.extend(else_instructions);
 
-        instructions
+        let (instructions, spans) = body.into_iter().unzip();
+        // Methods take their receiver as an extra first argument.
+        let arity = declaration.params.len() + usize::from(kind == FunctionKind::Method);
+        let mut function = Function::new(function_name(declaration), instructions, spans, arity);
+        function.doc = declaration.doc.clone();
+        function.captures = state.upvalues;
+        Ok(function)
     }
 
-    fn return_statement(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        if self.check(&Token::Semicolon) {
-            instructions.push(Instruction::Nil);
+    fn class_declaration(&mut self, class: &ClassDecl, span: Span) -> CompileResult<()> {
+        let superclass = class.superclass.as_ref().map(|name| self.get_variable(&name.name));
+        let global = self.scopes.len() == 1;
+        if global {
+            // A global class is kept in a temporary slot until it's defined, so that the slot of
+            // its `super` variable is known.
+            self.begin_scope();
+            self.define_local("<class>", class.name.span)?;
         } else {
-            instructions.extend(self.expression());
+            // Like a local function, a local class is declared before its methods are compiled.
+            self.define_local(&class.name.name, class.name.span)?;
+        }
+        if superclass.is_some() {
+            // Methods reach the superclass through a hidden local, which they capture like any
+            // other variable of the enclosing function.
+            self.begin_scope();
+            self.define_local("super", span)?;
         }
 
-        self.match_token(Token::Semicolon);
-
-        instructions.push(Instruction::Return);
-
-        instructions
-    }
-
-    fn while_statement(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        self.consume_token(Token::LeftParen, "Expect '(' after 'while'.");
-        let condition = self.expression();
-        let condition_length = condition.len();
-        instructions.extend(condition);
-        self.consume_token(Token::RightParen, "Expect ')' after condition.");
-
-        let body = self.block_statement();
-        let body_length = body.len();
-
-        // Example Instructions:
-        // [ True, JumpIfFalse(3), Constant(0), Print, Pop, JumpBack(4)
-
-        instructions.push(Instruction::JumpIfFalse(body_length + 2));
-        instructions.extend(body);
-        instructions.push(Instruction::JumpBack(body_length + condition_length + 1));
-
-        instructions
-    }
-
-    fn print_statement(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.expression();
-        instructions.push(Instruction::Print);
-
-        self.match_token(Token::Semicolon);
-
-        instructions
-    }
-
-    fn expression_statement(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.expression();
-
-        self.match_token(Token::Semicolon);
-
-        instructions.push(Instruction::Pop);
-
-        instructions
-    }
+        let enclosing_super = std::mem::replace(&mut self.current_super, superclass.is_some());
+        let mut methods = HashMap::new();
+        for method in &class.methods {
+            let name = function_name(method);
+            let function = self.function_body(method, FunctionKind::Method)?;
+            methods.insert(self.strings.intern(&name), function);
+        }
+        self.current_super = enclosing_super;
 
-    fn expression(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.assignment();
+        let mut class_value = Class::new(class.name.name.clone(), methods);
+        class_value.doc = class.doc.clone();
+        self.classes.push(class_value);
 
-        while self.match_token(Token::Equal) {
-            instructions.extend(self.assignment());
+        self.emit(Instruction::Class(self.classes.len() - 1), span);
+        if let Some(superclass) = superclass {
+            self.emit(superclass, span);
+            self.emit(Instruction::Inherit, span);
+            self.end_scope(span);
         }
 
-        instructions
+        if global {
+            // `DefineGlobal` pops the temporary.
+            self.define_global(&class.name.name, span);
+            self.scopes.pop();
+        }
+        Ok(())
     }
 
-    fn assignment(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.or();
-
-        if self.match_token(Token::Equal) {
-            let value = self.assignment();
+    fn expression(&mut self, expression: &Expr) -> CompileResult<()> {
+        let span = expression.span;
 
-            if let Some(name) = instructions.pop() {
-                if let Instruction::GetGlobal(index) = name {
-                    instructions.extend(value);
-                    instructions.push(Instruction::SetGlobal(index));
-                } else if let Instruction::GetLocal(index) = name {
-                    instructions.extend(value);
-                    instructions.push(Instruction::SetLocal(index));
-                } else if let Instruction::GetProperty(index) = name {
-                    instructions.extend(value);
-                    instructions.push(Instruction::SetProperty(index));
-                } else if let Instruction::GetUpvalue(index) = name {
-                    instructions.extend(value);
-                    instructions.push(Instruction::SetUpvalue(index));
-                } else {
-                    panic!("Invalid assignment target.");
+        match &expression.kind {
+            ExprKind::Number(value) => {
+                let index = self.add_constant(Value::Number(*value));
+                self.emit(Instruction::Constant(index), span);
+            }
+            ExprKind::String(value) => {
+                let value = self.strings.intern(value);
+                let index = self.add_constant(Value::String(value.into()));
+                self.emit(Instruction::Constant(index), span);
+            }
+            ExprKind::Boolean(true) => {
+                self.emit(Instruction::True, span);
+            }
+            ExprKind::Boolean(false) => {
+                self.emit(Instruction::False, span);
+            }
+            ExprKind::Nil => {
+                self.emit(Instruction::Nil, span);
+            }
+            ExprKind::Variable(name) => {
+                let instruction = self.get_variable(name);
+                self.emit(instruction, span);
+            }
+            ExprKind::Assign { name, value } => {
+                let instruction = match self.get_variable(&name.name) {
+                    Instruction::GetGlobal(index) => Instruction::SetGlobal(index),
+                    Instruction::GetLocal(index) => Instruction::SetLocal(index),
+                    Instruction::GetUpvalue(index) => Instruction::SetUpvalue(index),
+                    _ => return Err(error_at(TokenKind::Equal, span, "Invalid assignment target.")),
+                };
+                self.expression(value)?;
+                self.emit(instruction, name.span);
+            }
+            ExprKind::CompoundAssign { name, op, value } => {
+                let get = self.get_variable(&name.name);
+                let set = match get {
+                    Instruction::GetGlobal(index) => Instruction::SetGlobal(index),
+                    Instruction::GetLocal(index) => Instruction::SetLocal(index),
+                    Instruction::GetUpvalue(index) => Instruction::SetUpvalue(index),
+                    _ => return Err(error_at(compound_operator(*op), span, "Invalid assignment target.")),
+                };
+                self.emit(get, name.span);
+                self.expression(value)?;
+                self.emit(binary_instruction(*op), span);
+                self.emit(set, name.span);
+            }
+            ExprKind::CompoundSet { object, name, op, value } => {
+                self.expression(object)?;
+                let index = self.add_name(&name.name);
+                self.emit(Instruction::Dup, name.span);
+                self.emit(Instruction::GetProperty(index), name.span);
+                self.expression(value)?;
+                self.emit(binary_instruction(*op), span);
+                self.emit(Instruction::SetProperty(index), name.span);
+            }
+            ExprKind::Unary { op, operand } => {
+                self.expression(operand)?;
+                let instruction = match op {
+                    UnaryOp::Negate => Instruction::Negate,
+                    UnaryOp::Not => Instruction::Not,
+                    UnaryOp::BitNot => Instruction::BitNot,
+                };
+                self.emit(instruction, span);
+            }
+            ExprKind::Binary { left, op, right } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.emit(binary_instruction(*op), span);
+            }
+            ExprKind::Logical { left, op, right } => {
+                self.expression(left)?;
+                let instruction = match op {
+                    LogicalOp::And => Instruction::JumpIfFalseOrPop(0),
+                    LogicalOp::Or => Instruction::JumpIfTrueOrPop(0),
+                };
+                let end_jump = self.emit(instruction, span);
+                self.expression(right)?;
+                self.patch_jump(end_jump);
+            }
+            ExprKind::Call { callee, arguments } => self.call(callee, arguments, span)?,
+            ExprKind::Get { object, name } => {
+                self.expression(object)?;
+                let index = self.add_name(&name.name);
+                self.emit(Instruction::GetProperty(index), span);
+            }
+            ExprKind::Set { object, name, value } => {
+                self.expression(object)?;
+                let index = self.add_name(&name.name);
+                self.expression(value)?;
+                self.emit(Instruction::SetProperty(index), name.span);
+            }
+            ExprKind::Interpolation(parts) => {
+                for part in parts {
+                    self.expression(part)?;
                 }
-            } else {
-                panic!("Invalid assignment target.");
+                self.emit(Instruction::BuildString(parts.len()), span);
+            }
+            ExprKind::List(elements) => {
+                for element in elements {
+                    self.expression(element)?;
+                }
+                self.emit(Instruction::BuildList(elements.len()), span);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                self.emit(Instruction::BuildMap(entries.len()), span);
+            }
+            ExprKind::Index { object, index } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit(Instruction::GetIndex, span);
+            }
+            ExprKind::SetIndex { object, index, value } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.emit(Instruction::SetIndex, span);
+                self.emit(Instruction::Pop, span);
+            }
+            ExprKind::CompoundSetIndex { object, index, op, value } => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.emit(Instruction::DupPair, span);
+                self.emit(Instruction::GetIndex, span);
+                self.expression(value)?;
+                self.emit(binary_instruction(*op), span);
+                self.emit(Instruction::SetIndex, span);
+                self.emit(Instruction::Pop, span);
+            }
+            ExprKind::Function(function) => self.function(function, FunctionKind::Function)?,
+            ExprKind::This => {
+                let this = self.this(span)?;
+                self.emit(this, span);
+            }
+            ExprKind::Super { .. } => {
+                return Err(error_at(TokenKind::Super, span, "Superclass methods can only be called."));
             }
-
         }
 
-        instructions
+        Ok(())
     }
 
-    fn or(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.and();
-
-        while self.match_token(Token::Or) {
-            instructions.extend(self.and());
-            instructions.push(Instruction::Or);
+    /// Methods take their receiver as an explicit first argument. `object.name(...)` invokes
+    /// the method directly on the object and superclass calls push the receiver between the
+    /// callee and the arguments, while any other callee may be a method bound to its receiver.
+    fn call(&mut self, callee: &Expr, arguments: &[Expr], span: Span) -> CompileResult<()> {
+        let mut argument_count = arguments.len();
+
+        match &callee.kind {
+            ExprKind::Get { object, name } => {
+                self.expression(object)?;
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                let index = self.add_name(&name.name);
+                self.invoke(index, argument_count, span);
+                return Ok(());
+            }
+            ExprKind::Super { method } => {
+                if !self.current_super {
+                    return Err(error_at(TokenKind::Super, callee.span, "Can't use 'super' outside of a subclass."));
+                }
+                let superclass = self.hidden_variable("super").unwrap();
+                let index = self.add_name(&method.name);
+                self.emit(superclass, callee.span);
+                self.emit(Instruction::GetSuper(index), method.span);
+                let this = self.this(callee.span)?;
+                self.emit(this, callee.span);
+                argument_count += 1;
+            }
+            _ => self.expression(callee)?,
         }
 
-        instructions
-    }
-
-    fn and(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.equality();
-
-        while self.match_token(Token::And) {
-            instructions.extend(self.equality());
-            instructions.push(Instruction::And);
+        for argument in arguments {
+            self.expression(argument)?;
         }
 
-        instructions
+        self.emit(Instruction::Call(argument_count), span);
+        Ok(())
     }
 
-    fn equality(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.comparison();
-
-        while self.match_token(Token::BangEqual) {
-            instructions.extend(self.comparison());
-            instructions.push(Instruction::NotEqual);
-        }
-
-        while self.match_token(Token::EqualEqual) {
-            instructions.extend(self.comparison());
-            instructions.push(Instruction::Equal);
-        }
-
-        instructions
+    /// Emits an `Invoke` of the method named by the constant at `name`, giving the call site
+    /// its own inline cache slot.
+    fn invoke(&mut self, name: usize, argument_count: usize, span: Span) {
+        let cache = self.inline_cache_count;
+        self.inline_cache_count += 1;
+        self.emit(Instruction::Invoke(name, argument_count, cache), span);
     }
 
-    fn comparison(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.addition();
-
-        while self.match_token(Token::Greater) {
-            instructions.extend(self.addition());
-            instructions.push(Instruction::Greater);
-        }
-
-        while self.match_token(Token::GreaterEqual) {
-            instructions.extend(self.addition());
-            instructions.push(Instruction::GreaterEqual);
-        }
-
-        while self.match_token(Token::Less) {
-            instructions.extend(self.addition());
-            instructions.push(Instruction::Less);
-        }
-
-        while self.match_token(Token::LessEqual) {
-            instructions.extend(self.addition());
-            instructions.push(Instruction::LessEqual);
-        }
-
-        instructions
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        let code = self.current_code();
+        code.push((instruction, span));
+        code.len() - 1
     }
 
-    fn addition(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.multiplication();
-
-        while self.match_token(Token::Minus) {
-            instructions.extend(self.multiplication());
-            instructions.push(Instruction::Subtract);
-        }
+    /// Points the jump at `index` to the next instruction to be emitted.
+    fn patch_jump(&mut self, index: usize) {
+        let code = self.current_code();
+        let offset = code.len() - index;
+        code[index].0 = match code[index].0 {
+            Instruction::Jump(_) => Instruction::Jump(offset),
+            Instruction::JumpIfFalse(_) => Instruction::JumpIfFalse(offset),
+            Instruction::JumpIfFalseOrPop(_) => Instruction::JumpIfFalseOrPop(offset),
+            Instruction::JumpIfTrueOrPop(_) => Instruction::JumpIfTrueOrPop(offset),
+            instruction => unreachable!("Cannot patch {:?}", instruction),
+        };
+    }
 
-        while self.match_token(Token::Plus) {
-            instructions.extend(self.multiplication());
-            instructions.push(Instruction::Add);
-        }
+    fn emit_loop(&mut self, loop_start: usize, span: Span) {
+        let offset = self.current_code().len() - loop_start;
+        self.emit(Instruction::JumpBack(offset), span);
+    }
 
-        instructions
+    fn current_function(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
     }
 
-    fn multiplication(&mut self) -> Vec<Instruction> {
-        let mut instructions = self.unary();
+    fn current_code(&mut self) -> &mut Code {
+        &mut self.current_function().code
+    }
 
-        while self.match_token(Token::Slash) {
-            instructions.extend(self.unary());
-            instructions.push(Instruction::Divide);
-        }
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope::new());
+    }
 
-        while self.match_token(Token::Star) {
-            instructions.extend(self.unary());
-            instructions.push(Instruction::Multiply);
+    /// Closes the innermost scope, popping its locals off the top of the stack.
+    fn end_scope(&mut self, span: Span) {
+        let scope = self.scopes.pop().unwrap();
+        for local in scope.locals.into_iter().rev() {
+            self.pop_local(local, span);
         }
-
-        instructions
     }
 
-    fn unary(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        if self.match_token(Token::Bang) {
-            instructions.extend(self.unary());
-            instructions.push(Instruction::Not);
-        } else if self.match_token(Token::Minus) {
-            instructions.extend(self.unary());
-            instructions.push(Instruction::Negate);
+    /// Emits the instruction removing a local that went out of scope from the stack, closing
+    /// its upvalue if a closure captured it.
+    fn pop_local(&mut self, local: Local, span: Span) {
+        if local.captured {
+            self.emit(Instruction::CloseUpvalue, span);
+            let code = &mut self.current_function().code;
+            for exit in local.exits {
+                code[exit].0 = Instruction::CloseUpvalue;
+            }
         } else {
-            instructions.extend(self.call());
+            self.emit(Instruction::Pop, span);
         }
-
-        instructions
     }
 
-    fn call(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        instructions.extend(self.primary());
-
-        loop {
-            match self.peek().clone() {
-                Token::LeftParen => instructions.extend(self.finish_call()),
-                Token::Dot => instructions.extend(self.finish_get(instructions.clone())),
-                _ => break,
-            }
-        }
+    fn current_scope(&self) -> &Scope {
+        self.scopes.last().unwrap()
+    }
 
-        instructions
+    fn current_scope_mut(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
     }
 
-    fn finish_call(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
+    /// The number of locals in the open scopes of the current function. Locals are pushed in
+    /// declaration order and popped when their scope ends, so this is also the stack slot the
+    /// next local gets.
+    fn local_total(&self) -> usize {
+        let scope_base = self.functions.last().unwrap().scope_base;
+        self.scopes[scope_base..].iter().map(|scope| scope.locals.len()).sum()
+    }
 
-        self.consume_token(Token::LeftParen, "Expect '(' after function name.");
+    fn global_count(&self) -> usize {
+        self.globals.len()
+    }
 
-        let mut arguments: usize = 0;
+    fn global_names(&self) -> Vec<String> {
+        let mut names = vec![String::new(); self.global_count()];
+        for (name, index) in &self.globals {
+            names[*index] = name.clone();
+        }
+        names
+    }
 
-        while !self.match_token(Token::RightParen) {
-            if arguments > 0 {
-                self.consume_token(Token::Comma, "Expect ',' after function argument.");
+    fn add_constant(&mut self, value: Value) -> usize {
+        for (index, constant) in self.constants.iter().enumerate() {
+            if *constant == value {
+                return index;
             }
-
-            instructions.extend(self.expression());
-            arguments += 1;
         }
 
-        instructions.push(Instruction::Call(arguments));
-
-        instructions
+        self.constants.push(value);
+        self.constants.len() - 1
     }
 
-    fn finish_get(&mut self, vec1: Vec<Instruction>) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        self.consume_token(Token::Dot, "Expect '.' after object.");
-        let name = self.consume_identifier("Expect property name after '.'.");
-        let index = self.add_constant(Value::String(name.clone()));
-        if self.peek() == &Token::LeftParen {
-            let mut call = self.finish_call();
-            if let Some(Instruction::Call(arguments)) = call.pop() {
-                instructions.push(Instruction::GetProperty(index));
-                instructions.extend(vec1);
-                instructions.extend(call);
-                instructions.push(Instruction::Call(arguments + 1));
-            }
-        } else {
-            instructions.push(Instruction::GetProperty(index));
-        }
-
-        instructions
-    }
-
-    fn primary(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
-
-        match self.peek().clone() {
-            Token::False => {
-                instructions.push(Instruction::False);
-                self.advance();
-            },
-            Token::True => {
-                instructions.push(Instruction::True);
-                self.advance();
-            },
-            Token::Nil => {
-                instructions.push(Instruction::Nil);
-                self.advance();
-            },
-            Token::Number(value) => {
-                let index = self.add_constant(Value::Number(value));
-                instructions.push(Instruction::Constant(index));
-                self.advance();
-            },
-            Token::String(s) => {
-                let index = self.add_constant(Value::String(s));
-                instructions.push(Instruction::Constant(index));
-                self.advance();
-            },
-            Token::Identifier(name) => {
-                instructions.push(self.get_variable(&name));
-                self.advance();
-            },
-            Token::LeftParen => {
-                self.advance();
-                instructions.extend(self.expression());
-                self.consume_token(Token::RightParen, "Expect ')' after expression.");
-            },
-            Token::Fn => {
-                self.advance();
-                instructions.extend(self.function(FunctionKind::Anonymous));
-            },
-            Token::Super => {
-                if let Some(superclass) = self.current_super {
-                    self.advance();
-                    self.consume_token(Token::Dot, "Expect '.' after 'super'.");
-                    let method = self.consume_identifier("Expect superclass method name.");
-                    let index = self.add_constant(Value::String(method));
-                    let mut call = self.finish_call();
-                    if let Some(Instruction::Call(arguments)) = call.pop() {
-                        instructions.push(superclass);
-                        instructions.push(Instruction::GetSuper(index));
-                        instructions.push(Instruction::GetLocal(0));
-                        instructions.extend(call);
-                        instructions.push(Instruction::Call(arguments + 1));
-                    }
-
-                } else {
-                    panic!("No superclass defined.");
-                }
-            },
-            Token::This => {
-                instructions.push(Instruction::GetLocal(0));
-                self.advance();
-            },
-            _ => {
-                panic!("Expected expression, got {:?}", self.peek());
+    /// Returns the index of `name` in the name table, adding it if it isn't there yet.
+    fn add_name(&mut self, name: &str) -> usize {
+        let name = self.strings.intern(name);
+        match self.names.iter().position(|existing| *existing == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
             }
         }
-
-        instructions
     }
 
     fn get_native(&mut self, name: &str) -> Instruction {
@@ -669,23 +885,20 @@ impl Compiler {
     }
 
     fn get_native_class(&mut self, name: &str) -> Instruction {
-        let index = self.add_constant(Value::Class(NATIVE_CLASSES[name].clone()));
-        Instruction::Constant(index)
+        Instruction::GetNativeClass(self.add_name(name))
     }
 
     fn get_variable(&mut self, name: &str) -> Instruction {
 
         if NATIVE_FUNCTIONS.contains_key(name) {
-            self.get_native(&name)
-        } else if NATIVE_CLASSES.contains_key(name) {
-            self.get_native_class(&name)
+            self.get_native(name)
+        } else if NATIVE_CLASS_NAMES.contains(&name) {
+            self.get_native_class(name)
         } else {
-            let local_index = self.get_local_index(name);
-            let upvalue_index = self.get_upvalue_index(name);
-            return if let Some(upvalue_index) = upvalue_index {
-                Instruction::GetUpvalue(upvalue_index)
-            } else if let Some(local_index) = local_index {
+            if let Some(local_index) = self.get_local_index(name) {
                 Instruction::GetLocal(local_index)
+            } else if let Some(upvalue_index) = self.resolve_upvalue(self.functions.len() - 1, name) {
+                Instruction::GetUpvalue(upvalue_index)
             } else {
                 if !self.globals.contains_key(name) {
                     self.globals.insert(name.to_string(), self.global_count());
@@ -697,170 +910,434 @@ impl Compiler {
         }
     }
 
-    fn set_variable<S: ToString>(&mut self, name: S) -> Instruction {
-        let local_index = self.get_local_index(&name.to_string());
-        let upvalue_index = self.get_upvalue_index(&name.to_string());
-        return if let Some(upvalue_index) = upvalue_index {
-            Instruction::SetUpvalue(upvalue_index)
-        } else if let Some(local_index) = local_index {
-            Instruction::SetLocal(local_index)
-        } else {
-            let global_index = self.global_count();
-            self.globals.entry(name.to_string()).or_insert_with(|| global_index);
+    /// Resolves the receiver of the innermost enclosing method. Methods declare it as their first
+    /// local, so functions nested in a method capture it like any other variable.
+    fn this(&mut self, span: Span) -> CompileResult<Instruction> {
+        self.hidden_variable("this")
+            .ok_or_else(|| error_at(TokenKind::This, span, "Can't use 'this' outside of a class."))
+    }
 
-            let index = self.globals.get(&name.to_string()).unwrap();
-            Instruction::SetGlobal(*index)
+    /// Resolves a variable only the compiler declares, like `this` or the `super` of a subclass,
+    /// as a local or an upvalue. Unlike other variables, it's never a global.
+    fn hidden_variable(&mut self, name: &str) -> Option<Instruction> {
+        if let Some(slot) = self.get_local_index(name) {
+            Some(Instruction::GetLocal(slot))
+        } else {
+            self.resolve_upvalue(self.functions.len() - 1, name).map(Instruction::GetUpvalue)
         }
     }
 
+    /// Looks a name up in the scopes of the current function, innermost first.
     fn get_local_index(&mut self, name: &str) -> Option<usize> {
-        if self.in_function {
-            return self.scopes.last().unwrap().locals.get(name).copied()
-        }
-        for scope in &self.scopes {
-            if scope.locals.contains_key(name) {
-                return Some(scope.locals[name]);
-            }
-        }
-        None
+        let scope_base = self.functions.last().unwrap().scope_base;
+        self.scopes[scope_base..]
+            .iter()
+            .rev()
+            .find_map(|scope| scope.local(name))
     }
 
-    fn get_upvalue_index(&mut self, name: &str) -> Option<usize> {
-        for (i, scope) in self.scopes.iter_mut().enumerate().rev().skip(1) {
-            if scope.upvalues.contains_key(name) {
-                return Some(scope.upvalues[name].upvalue_index);
-            }
+    /// Resolves `name` as a variable the function at `function` captures from the functions
+    /// enclosing it. A capture of a local marks the local as captured, and a capture from
+    /// further out adds an upvalue to every function in between.
+    fn resolve_upvalue(&mut self, function: usize, name: &str) -> Option<usize> {
+        if function == 0 {
+            return None;
         }
 
-        for (i, scope) in self.scopes.iter_mut().enumerate().rev().skip(1) {
-            if scope.locals.contains_key(name) {
-                let local_index = scope.locals[name];
-                scope.upvalues.insert(name.to_string(), Upvalue {
-                    is_local: true,
-                    local_index: scope.locals[name],
-                    upvalue_index: self.upvalue_count,
-                });
-                self.upvalue_count += 1;
-                for scope in &mut self.scopes[i + 1..] {
-                    scope.upvalues.insert(name.to_string(), Upvalue {
-                        is_local: false,
-                        local_index,
-                        upvalue_index: self.upvalue_count - 1,
-                    });
-                }
-                return Some(self.upvalue_count - 1);
+        let enclosing = function - 1;
+        let scopes = self.functions[enclosing].scope_base..self.functions[function].scope_base;
+        let local = self.scopes[scopes]
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.locals.iter_mut().find(|local| local.name == name));
+
+        let capture = match local {
+            Some(local) => {
+                local.captured = true;
+                Capture::Local(local.slot)
+            }
+            None => Capture::Upvalue(self.resolve_upvalue(enclosing, name)?),
+        };
+
+        let upvalues = &mut self.functions[function].upvalues;
+        match upvalues.iter().position(|&upvalue| upvalue == capture) {
+            Some(index) => Some(index),
+            None => {
+                upvalues.push(capture);
+                Some(upvalues.len() - 1)
             }
         }
-        None
     }
+}
 
-    fn is_at_end(&self) -> bool {
-        self.peek() == &Token::Eof
+fn function_name(function: &FunctionDecl) -> String {
+    match &function.name {
+        Some(name) => name.name.clone(),
+        None => "<anonymous>".to_string(),
     }
+}
 
-    fn advance(&mut self) {
-        self.current += 1;
+fn binary_instruction(op: BinaryOp) -> Instruction {
+    match op {
+        BinaryOp::Add => Instruction::Add,
+        BinaryOp::Subtract => Instruction::Subtract,
+        BinaryOp::Multiply => Instruction::Multiply,
+        BinaryOp::Divide => Instruction::Divide,
+        BinaryOp::Modulo => Instruction::Modulo,
+        BinaryOp::Power => Instruction::Power,
+        BinaryOp::BitAnd => Instruction::BitAnd,
+        BinaryOp::BitOr => Instruction::BitOr,
+        BinaryOp::BitXor => Instruction::BitXor,
+        BinaryOp::ShiftLeft => Instruction::ShiftLeft,
+        BinaryOp::ShiftRight => Instruction::ShiftRight,
+        BinaryOp::Equal => Instruction::Equal,
+        BinaryOp::NotEqual => Instruction::NotEqual,
+        BinaryOp::Greater => Instruction::Greater,
+        BinaryOp::GreaterEqual => Instruction::GreaterEqual,
+        BinaryOp::Less => Instruction::Less,
+        BinaryOp::LessEqual => Instruction::LessEqual,
     }
+}
 
-    fn check(&self, token: &Token) -> bool {
-        self.peek() == token
+/// The `op=` token a compound assignment with `op` was written with.
+fn compound_operator(op: BinaryOp) -> TokenKind {
+    match op {
+        BinaryOp::Add => TokenKind::PlusEqual,
+        BinaryOp::Subtract => TokenKind::MinusEqual,
+        BinaryOp::Multiply => TokenKind::StarEqual,
+        BinaryOp::Divide => TokenKind::SlashEqual,
+        BinaryOp::Modulo => TokenKind::PercentEqual,
+        _ => unreachable!("the parser has no compound assignment for {:?}", op),
     }
+}
 
-    fn match_token(&mut self, token: Token) -> bool {
-        if self.check(&token) {
-            self.advance();
-            true
-        } else {
-            false
-        }
+fn error_at(kind: TokenKind, span: Span, message: &str) -> CompileError {
+    CompileError {
+        message: message.to_string(),
+        token: Token::new(kind, span),
+        expected: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use super::*;
+
+    fn compile(source: &str) -> Program {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        Compiler::new().compile(&statements).unwrap()
+    }
+
+    fn compile_errors(source: &str) -> Vec<CompileError> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        Compiler::new().compile(&statements).unwrap_err()
+    }
+
+    #[test]
+    fn test_let_statements() {
+        let program = compile("let x = 5;");
+        assert_eq!(program.instructions, vec![
+            Instruction::Constant(0),
+            Instruction::DefineGlobal(0),
+            Instruction::Halt,
+        ]);
+        assert_eq!(program.constants, vec![
+            Value::Number(5.0),
+        ]);
+        assert_eq!(program.global_count, 1);
+    }
+
+    #[test]
+    fn test_let_statements_2() {
+        let program = compile("let x = 5; let y = 10;");
+        assert_eq!(program.instructions, vec![
+            Instruction::Constant(0),
+            Instruction::DefineGlobal(0),
+            Instruction::Constant(1),
+            Instruction::DefineGlobal(1),
+            Instruction::Halt,
+        ]);
+        assert_eq!(program.constants, vec![
+            Value::Number(5.0),
+            Value::Number(10.0),
+        ]);
+        assert_eq!(program.global_count, 2);
     }
 
-    fn peek(&self) -> &Token {
-        &self.tokens[self.current]
+    #[test]
+    fn test_local_slots_follow_stack_depth() {
+        let program = compile("{ let a = 1; { let b = 2; } { let c = a; } let d = a; }");
+        assert_eq!(program.instructions, vec![
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Pop,
+            Instruction::GetLocal(0),
+            Instruction::Pop,
+            Instruction::GetLocal(0),
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
+
+        let program = compile("fn f(a, b) { let c = b; { let d = a; return d + c; } }");
+        let Value::Function(function) = &program.constants[0] else {
+            panic!("Expected function constant.");
+        };
+        assert_eq!(function.instructions[..6], [
+            Instruction::GetLocal(1),
+            Instruction::GetLocal(0),
+            Instruction::GetLocal(3),
+            Instruction::GetLocal(2),
+            Instruction::Add,
+            Instruction::Return,
+        ]);
+    }
+
+    #[test]
+    fn test_captured_locals_are_closed_when_popped() {
+        let program = compile("{ let a = 1; let b = 2; let f = fn() { return a; }; }");
+        assert_eq!(program.instructions[3..], [
+            Instruction::MakeClosure,
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::CloseUpvalue,
+            Instruction::Halt,
+        ]);
+
+        // `break` pops `a` before the closure capturing it is compiled.
+        let program = compile("while (true) { let a = 1; break; let f = fn() { return a; }; }");
+        assert_eq!(program.instructions, vec![
+            Instruction::True,
+            Instruction::JumpIfFalse(9),
+            Instruction::Constant(0),
+            Instruction::CloseUpvalue,
+            Instruction::Jump(6),
+            Instruction::Constant(1),
+            Instruction::MakeClosure,
+            Instruction::Pop,
+            Instruction::CloseUpvalue,
+            Instruction::JumpBack(9),
+            Instruction::Halt,
+        ]);
     }
 
-    fn peek_next(&self) -> &Token {
-        &self.tokens[self.current + 1]
+    #[test]
+    fn test_instruction_spans() {
+        let program = compile("let x = 5;\nprint x + 1;");
+        let positions: Vec<(usize, usize)> = program.spans.iter().map(|span| (span.line, span.column)).collect();
+        assert_eq!(program.instructions.len(), program.spans.len());
+        assert_eq!(positions, vec![
+            (1, 9),  // Constant(5)
+            (1, 5),  // DefineGlobal(x)
+            (2, 7),  // GetGlobal(x)
+            (2, 11), // Constant(1)
+            (2, 9),  // Add
+            (2, 1),  // Print
+            (2, 1),  // Halt
+        ]);
     }
 
-    fn consume_token(&mut self, token: Token, message: &str) {
-        if self.check(&token) {
-            self.advance();
+    #[test]
+    fn test_function_spans() {
+        let program = compile("fn f() {\n  return nil;\n}");
+        if let Value::Function(function) = &program.constants[0] {
+            assert_eq!(function.instructions, vec![Instruction::Nil, Instruction::Return]);
+            assert_eq!(function.span_at(1).line, 2);
         } else {
-            panic!("{} Expected {:?}. Got {:?}", message, token, self.peek());
+            panic!("Expected function constant.");
         }
     }
 
-    fn block(&mut self) -> Vec<Instruction> {
-        let mut instructions = vec![];
+    #[test]
+    fn test_jumps() {
+        let program = compile("while (x) { if (y) { print 1; } else { print 2; } }");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::JumpIfFalse(9),
+            Instruction::GetGlobal(1),
+            Instruction::JumpIfFalse(4),
+            Instruction::Constant(0),
+            Instruction::Print,
+            Instruction::Jump(3),
+            Instruction::Constant(1),
+            Instruction::Print,
+            Instruction::JumpBack(9),
+            Instruction::Halt,
+        ]);
+    }
 
-        self.consume_token(Token::LeftBrace, "Expect '{' before block.");
+    #[test]
+    fn test_logical_operators_jump_over_right_operand() {
+        let program = compile("a and b or c;");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::JumpIfFalseOrPop(2),
+            Instruction::GetGlobal(1),
+            Instruction::JumpIfTrueOrPop(2),
+            Instruction::GetGlobal(2),
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
+    }
 
-        while !self.check(&Token::RightBrace) && !self.is_at_end() {
-            instructions.extend(self.declaration());
-        }
+    #[test]
+    fn test_break_pops_locals_of_exited_scopes() {
+        let program = compile("while (x) { let a = 1; { let b = 2; break; } }");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::JumpIfFalse(9),
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::Jump(4),
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::JumpBack(9),
+            Instruction::Halt,
+        ]);
+    }
 
-        self.consume_token(Token::RightBrace, "Expect '}' after block.");
+    #[test]
+    fn test_loop_control_errors() {
+        let errors = compile_errors("
+            break;
+            while (true) { fn f() { continue; } }
+            while (true) { break outer; }
+        ");
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Can't use 'break' outside of a loop.",
+            "Can't use 'continue' outside of a loop.",
+            "Undefined loop label 'outer'.",
+        ]);
+        let lines: Vec<usize> = errors.iter().map(|error| error.token.span.line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+    }
 
-        instructions
+    #[test]
+    fn test_index_assignment_discards_set_result() {
+        let program = compile("a[0] = [1];");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::Constant(0),
+            Instruction::Constant(1),
+            Instruction::BuildList(1),
+            Instruction::SetIndex,
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
     }
 
-    fn consume_identifier(&mut self, message: &str) -> String {
-        let identifier = self.peek();
+    #[test]
+    fn test_method_calls_are_invoked_with_a_cache_per_site() {
+        let program = compile("a.m(1); a.m(2);");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::Constant(0),
+            Instruction::Invoke(0, 1, 0),
+            Instruction::Pop,
+            Instruction::GetGlobal(0),
+            Instruction::Constant(1),
+            Instruction::Invoke(0, 1, 1),
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
+        assert_eq!(program.inline_cache_count, 2);
+        assert_eq!(program.names.len(), 1);
+        assert_eq!(&*program.names[0], "m");
+    }
 
-        if let Token::Identifier(name) = identifier.clone() {
-            self.advance();
-            name
-        } else {
-            panic!("{} Expected identifier.", message);
-        }
+    #[test]
+    fn test_compound_property_assignment_evaluates_object_once() {
+        let program = compile("a.b += 1;");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::Dup,
+            Instruction::GetProperty(0),
+            Instruction::Constant(0),
+            Instruction::Add,
+            Instruction::SetProperty(0),
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::scanner::Scanner;
-    use super::*;
+    #[test]
+    fn test_functions_are_constants_by_identity() {
+        let program = compile("let a = fn() { return 1; }; let b = fn() { return 1; };");
+        let functions: Vec<&Value> = program.constants.iter().filter(|constant| matches!(constant, Value::Function(_))).collect();
+        assert_eq!(functions.len(), 2);
+        assert_ne!(functions[0], functions[1]);
+        assert_eq!(functions[0], &functions[0].clone());
+    }
 
-    fn compile(source: &str) -> Program {
-        let mut scanner = Scanner::new(source);
-        scanner.scan_tokens();
-        let mut compiler = Compiler::new(scanner.tokens);
-        compiler.compile()
+    #[test]
+    fn test_compound_index_assignment_evaluates_object_and_index_once() {
+        let program = compile("a[0] -= 1;");
+        assert_eq!(program.instructions, vec![
+            Instruction::GetGlobal(0),
+            Instruction::Constant(0),
+            Instruction::DupPair,
+            Instruction::GetIndex,
+            Instruction::Constant(1),
+            Instruction::Subtract,
+            Instruction::SetIndex,
+            Instruction::Pop,
+            Instruction::Pop,
+            Instruction::Halt,
+        ]);
     }
 
     #[test]
-    fn test_let_statements() {
-        let program = compile("let x = 5;");
-        assert_eq!(program, Program {
-            instructions: vec![
-                Instruction::Constant(0),
-                Instruction::DefineGlobal(0),
-                Instruction::Halt,
-            ],
-            constants: vec![
-                Value::Number(5.0),
-            ],
-            global_count: 1
-        });
+    fn test_compound_assignment_to_native_reports_operator() {
+        let errors = compile_errors("let x = 1;\nreadln += 1;");
+        assert_eq!(errors[0].message, "Invalid assignment target.");
+        assert_eq!(errors[0].token.kind, TokenKind::PlusEqual);
+        assert_eq!((errors[0].token.span.line, errors[0].token.span.column), (2, 8));
     }
 
     #[test]
-    fn test_let_statements_2() {
-        let program = compile("let x = 5; let y = 10;");
-        assert_eq!(program, Program {
-            instructions: vec![
-                Instruction::Constant(0),
-                Instruction::DefineGlobal(0),
-                Instruction::Constant(1),
-                Instruction::DefineGlobal(1),
-                Instruction::Halt,
-            ],
-            constants: vec![
-                Value::Number(5.0),
-                Value::Number(10.0),
-            ],
-            global_count: 2
-        });
+    fn test_this_is_captured_by_closures_in_methods() {
+        let program = compile("class A { m() { return fn() { return this; }; } }");
+        let Some(Value::Closure(method)) = program.classes[0].methods.values().next() else {
+            panic!("Expected method.");
+        };
+        let Value::Function(closure) = &program.constants[0] else {
+            panic!("Expected closure constant.");
+        };
+        assert_eq!(closure.captures, vec![Capture::Local(0)]);
+        assert_eq!(closure.instructions[0], Instruction::GetUpvalue(0));
+        assert!(method.function.captures.is_empty());
+
+        let errors = compile_errors("
+            print this;
+            fn f() { return fn() { return this; }; }
+        ");
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(messages, vec!["Can't use 'this' outside of a class.", "Can't use 'this' outside of a class."]);
+        assert_eq!(errors[1].token.kind, TokenKind::This);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_codegen_errors() {
+        let errors = compile_errors("
+            super.m();
+            { let a = 1; let a = 2; }
+            readln = 1;
+        ");
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Can't use 'super' outside of a subclass.",
+            "Variable with this name already defined in the same scope: a",
+            "Invalid assignment target.",
+        ]);
+        let lines: Vec<usize> = errors.iter().map(|error| error.token.span.line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+    }
+}
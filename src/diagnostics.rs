@@ -0,0 +1,326 @@
+use std::fmt::Write;
+use crate::compiler::CompileError;
+use crate::scanner::{ScanError, ScanErrorKind};
+use crate::span::Span;
+use crate::vm::RuntimeError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        }
+    }
+}
+
+/// A message about a location in a source file, shared by scanner, compiler and VM errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    /// Short text printed next to the underline.
+    pub label: Option<String>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error<S: ToString>(message: S) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: None,
+            label: None,
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Diagnostic {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_label<S: ToString>(mut self, label: S) -> Diagnostic {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_note<S: ToString>(mut self, note: S) -> Diagnostic {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn with_help<S: ToString>(mut self, help: S) -> Diagnostic {
+        self.help = Some(help.to_string());
+        self
+    }
+}
+
+impl From<&ScanError> for Diagnostic {
+    fn from(error: &ScanError) -> Diagnostic {
+        let diagnostic = Diagnostic::error(&error.kind).with_span(error.span);
+        match error.kind {
+            ScanErrorKind::UnexpectedCharacter(_) => diagnostic.with_label("unexpected character"),
+            ScanErrorKind::UnterminatedString => diagnostic
+                .with_label("string starts here")
+                .with_help("add a closing quote"),
+            ScanErrorKind::UnterminatedComment => diagnostic
+                .with_label("comment starts here")
+                .with_help("add a closing '*/'"),
+            ScanErrorKind::MalformedNumber(_) => diagnostic.with_label("invalid number"),
+            ScanErrorKind::InvalidEscape(_) => diagnostic.with_label("invalid escape"),
+        }
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Diagnostic {
+        let diagnostic = Diagnostic::error(&error.message).with_span(error.token.span);
+        match &error.expected {
+            Some(expected) => diagnostic
+                .with_label(format!("expected {}", expected))
+                .with_note(format!("found '{}'", error.token.kind)),
+            None => diagnostic,
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Diagnostic {
+        let mut diagnostic = Diagnostic::error(&error.message);
+        if let Some(span) = error.span() {
+            diagnostic = diagnostic.with_span(span);
+        }
+        for frame in &error.trace {
+            diagnostic = diagnostic.with_note(format!("in {} at {}", frame.function, frame.span));
+        }
+        diagnostic
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// rustc-style output with source snippets and ANSI colors.
+    Human,
+    /// The same layout as `Human` without colors, for logs.
+    Plain,
+    /// One JSON object per diagnostic and line, for editors.
+    Json,
+}
+
+pub struct Renderer<'a> {
+    source: &'a str,
+    file: &'a str,
+    format: Format,
+}
+
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl<'a> Renderer<'a> {
+    pub fn new(source: &'a str, file: &'a str, format: Format) -> Renderer<'a> {
+        Renderer {
+            source,
+            file,
+            format,
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.format {
+            Format::Human => self.render_text(diagnostic, true),
+            Format::Plain => self.render_text(diagnostic, false),
+            Format::Json => self.render_json(diagnostic),
+        }
+    }
+
+    fn render_text(&self, diagnostic: &Diagnostic, color: bool) -> String {
+        let paint = |style: &'static str| if color { style } else { "" };
+        let reset = paint(RESET);
+        let gutter_color = paint(BLUE);
+        let severity_color = paint(diagnostic.severity.color());
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{}{}: {}{}{}",
+            severity_color, diagnostic.severity.as_str(), reset, paint(BOLD), diagnostic.message, reset
+        );
+
+        let span = match diagnostic.span {
+            Some(span) => span,
+            None => {
+                let _ = writeln!(out, "{}-->{} {}", gutter_color, reset, self.file);
+                self.render_footer(&mut out, diagnostic, "", gutter_color, reset);
+                return out;
+            }
+        };
+
+        let line_number = span.line.to_string();
+        let pad = " ".repeat(line_number.len());
+        let line = self.source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+
+        // Everything before the span is replaced with blanks, keeping tabs so the caret lines up.
+        let prefix: String = line
+            .chars()
+            .take(span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = line.chars().count().saturating_sub(span.column.saturating_sub(1));
+        let width = self.source
+            .get(span.start..span.end)
+            .map(|text| text.lines().next().unwrap_or("").chars().count())
+            .unwrap_or(0)
+            .clamp(1, remaining.max(1));
+
+        let _ = writeln!(out, "{}{}-->{} {}:{}:{}", pad, gutter_color, reset, self.file, span.line, span.column);
+        let _ = writeln!(out, "{} {}|{}", pad, gutter_color, reset);
+        let _ = writeln!(out, "{}{} |{} {}", gutter_color, line_number, reset, line);
+        let _ = write!(out, "{} {}|{} {}{}{}", pad, gutter_color, reset, prefix, severity_color, "^".repeat(width));
+        if let Some(label) = &diagnostic.label {
+            let _ = write!(out, " {}", label);
+        }
+        let _ = writeln!(out, "{}", reset);
+
+        self.render_footer(&mut out, diagnostic, &pad, gutter_color, reset);
+        out
+    }
+
+    fn render_footer(&self, out: &mut String, diagnostic: &Diagnostic, pad: &str, gutter_color: &str, reset: &str) {
+        if diagnostic.notes.is_empty() && diagnostic.help.is_none() {
+            return;
+        }
+
+        let _ = writeln!(out, "{} {}|{}", pad, gutter_color, reset);
+        for note in &diagnostic.notes {
+            let _ = writeln!(out, "{} {}={} note: {}", pad, gutter_color, reset, note);
+        }
+        if let Some(help) = &diagnostic.help {
+            let _ = writeln!(out, "{} {}={} help: {}", pad, gutter_color, reset, help);
+        }
+    }
+
+    fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = String::from("{");
+        let _ = write!(out, "\"severity\":{}", json_string(diagnostic.severity.as_str()));
+        let _ = write!(out, ",\"message\":{}", json_string(&diagnostic.message));
+        let _ = write!(out, ",\"file\":{}", json_string(self.file));
+        if let Some(span) = diagnostic.span {
+            let _ = write!(
+                out,
+                ",\"line\":{},\"column\":{},\"start\":{},\"end\":{}",
+                span.line, span.column, span.start, span.end
+            );
+        }
+        if let Some(label) = &diagnostic.label {
+            let _ = write!(out, ",\"label\":{}", json_string(label));
+        }
+        let notes: Vec<String> = diagnostic.notes.iter().map(|note| json_string(note)).collect();
+        let _ = write!(out, ",\"notes\":[{}]", notes.join(","));
+        if let Some(help) = &diagnostic.help {
+            let _ = write!(out, ",\"help\":{}", json_string(help));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_plain() {
+        let source = "let x = 1;\nprint x +;\n";
+        let diagnostic = Diagnostic::error("Expect expression.")
+            .with_span(Span::new(20, 21, 2, 10))
+            .with_label("expected expression")
+            .with_help("remove the trailing operator");
+        let rendered = Renderer::new(source, "main.horst", Format::Plain).render(&diagnostic);
+        assert_eq!(rendered, "\
+error: Expect expression.
+ --> main.horst:2:10
+  |
+2 | print x +;
+  |          ^ expected expression
+  |
+  = help: remove the trailing operator
+");
+    }
+
+    #[test]
+    fn test_render_underlines_whole_span() {
+        let source = "\tfoo(bar, baz);";
+        let diagnostic = Diagnostic::error("Undefined variable.").with_span(Span::new(5, 8, 1, 6));
+        let rendered = Renderer::new(source, "a.horst", Format::Plain).render(&diagnostic);
+        assert!(rendered.contains("1 | \tfoo(bar, baz);\n  | \t    ^^^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_human_uses_colors() {
+        let diagnostic = Diagnostic::error("Boom.").with_span(Span::new(0, 1, 1, 1));
+        let rendered = Renderer::new("x", "a.horst", Format::Human).render(&diagnostic);
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m: \x1b[1mBoom.\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_render_json() {
+        let diagnostic = Diagnostic::error("Unexpected character '\"'.")
+            .with_span(Span::new(3, 4, 1, 4))
+            .with_note("a\nb");
+        let rendered = Renderer::new("", "dir\\a.horst", Format::Json).render(&diagnostic);
+        assert_eq!(
+            rendered,
+            "{\"severity\":\"error\",\"message\":\"Unexpected character '\\\"'.\",\"file\":\"dir\\\\a.horst\",\
+\"line\":1,\"column\":4,\"start\":3,\"end\":4,\"notes\":[\"a\\nb\"]}\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_error_diagnostic() {
+        use crate::parser::Parser;
+        use crate::scanner::Scanner;
+
+        let tokens = Scanner::new("print (1;").scan_tokens().unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        let diagnostic = Diagnostic::from(&errors[0]);
+        assert_eq!(diagnostic.label, Some("expected ')'".to_string()));
+        assert_eq!(diagnostic.notes, vec!["found ';'".to_string()]);
+    }
+}
@@ -1,8 +1,17 @@
-use crate::function::Function;
+use std::rc::Rc;
+use crate::function::Closure;
+use crate::span::Span;
 
 #[derive(Debug, Clone)]
 pub struct CallFrame {
-    pub function: Function,
+    pub closure: Rc<Closure>,
     pub ip: usize,
     pub base_pointer: usize,
+}
+
+impl CallFrame {
+    /// Returns the source span of the instruction this frame is executing.
+    pub fn span(&self) -> Span {
+        self.closure.function.span_at(self.ip.saturating_sub(1))
+    }
 }
\ No newline at end of file
@@ -1,20 +1,64 @@
-use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
+use std::rc::Rc;
 use crate::instruction::Instruction;
+use crate::span::Span;
 use crate::value::Value;
-use crate::vm::VM;
+use crate::vm::{RuntimeError, VM};
 
-#[derive(Clone, PartialEq, Debug)]
+/// The compiled code of a function, shared by all closures created from it. Functions have no
+/// `PartialEq`: values holding them compare by identity instead.
+#[derive(Clone, Debug)]
 pub struct Function {
+    pub name: String,
     pub instructions: Vec<Instruction>,
+    pub spans: Vec<Span>,
     pub arity: usize,
-    pub upvalues: HashMap<usize, usize>,
+    /// Where a closure finds the variables it captures when it is created.
+    pub captures: Vec<Capture>,
+    /// The declaration's `///` comment, available at runtime through the `doc` native.
+    pub doc: Option<String>,
+}
+
+/// A variable of an enclosing function captured by a closure.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Capture {
+    /// A local of the function creating the closure, by stack slot.
+    Local(usize),
+    /// An upvalue of the function creating the closure, by index.
+    Upvalue(usize),
+}
+
+/// A function together with the variables it captured, which is what Horst code calls.
+#[derive(Clone, Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    /// The heap ids of the closure's upvalues, indexed like the function's `captures`.
+    pub upvalues: Vec<usize>,
+}
+
+impl Closure {
+    /// A closure that doesn't capture anything, as for methods and the script.
+    pub fn new(function: Rc<Function>) -> Closure {
+        Closure { function, upvalues: vec![] }
+    }
+}
+
+/// A method read off an object, remembering the object so that calling the method later
+/// passes it as the receiver.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    /// A `Value::Closure` or `Value::Native` taking the receiver as its first argument.
+    pub method: Value,
 }
 
 #[derive(Clone)]
 pub struct NativeFunction {
-    pub function: fn(Vec<Value>, &mut VM) -> Value,
+    pub function: fn(Vec<Value>, &mut VM) -> Result<Value, RuntimeError>,
+    /// The number of arguments, counting the receiver of methods, or `None` for natives like
+    /// `List`'s `init` that take any number. The VM checks it before the call.
+    pub arity: Option<usize>,
 }
 
 impl PartialEq for NativeFunction {
@@ -33,17 +77,25 @@ impl fmt::Debug for NativeFunction {
         let fn_ptr = self.function as usize;
 
         // Format the output as a hexadecimal address
-        write!(f, "NativeFunction {{ function: {:x?} }}", fn_ptr)
+        write!(f, "NativeFunction {{ function: {:x?}, arity: {:?} }}", fn_ptr, self.arity)
     }
 }
 
 
 impl Function {
-    pub fn new(instructions: Vec<Instruction>, arity: usize) -> Function {
+    pub fn new<S: ToString>(name: S, instructions: Vec<Instruction>, spans: Vec<Span>, arity: usize) -> Function {
         Function {
+            name: name.to_string(),
             instructions,
+            spans,
             arity,
-            upvalues: HashMap::new(),
+            captures: vec![],
+            doc: None,
         }
     }
+
+    /// Returns the source span of the instruction at `ip`.
+    pub fn span_at(&self, ip: usize) -> Span {
+        self.spans.get(ip).copied().unwrap_or_default()
+    }
 }
\ No newline at end of file
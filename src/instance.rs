@@ -1,18 +1,19 @@
 use std::collections::HashMap;
-use crate::class::Class;
+use crate::symbol::Symbol;
 use crate::value::Value;
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Instance {
-    pub class: Class,
-    pub fields: HashMap<String, Value>,
+    /// The heap id of the instance's class.
+    pub class: usize,
+    pub fields: HashMap<Symbol, Value>,
 }
 
 impl Instance {
-    pub fn new(class: Class) -> Instance {
+    pub fn new(class: usize) -> Instance {
         Instance {
             class,
             fields: HashMap::new()
         }
     }
-}
\ No newline at end of file
+}
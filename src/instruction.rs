@@ -5,12 +5,27 @@ pub enum Instruction {
     False,
     Nil,
     Pop,
-    And,
-    Or,
+    /// Closes the upvalue capturing the top of the stack, if there is one, and pops it.
+    CloseUpvalue,
+    /// Pushes a copy of the top of the stack.
+    Dup,
+    /// Pushes copies of the top two values of the stack, in the same order.
+    DupPair,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Power,
+    /// The bitwise instructions require both operands to be integral numbers and operate on
+    /// their 64-bit two's complement representation.
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    /// An arithmetic shift that preserves the sign.
+    ShiftRight,
+    BitNot,
     Negate,
     Not,
     Equal,
@@ -22,6 +37,10 @@ pub enum Instruction {
     Jump(usize),
     JumpBack(usize),
     JumpIfFalse(usize),
+    /// Jumps if the top of the stack is falsey and leaves it there, otherwise pops it.
+    JumpIfFalseOrPop(usize),
+    /// Jumps if the top of the stack is truthy and leaves it there, otherwise pops it.
+    JumpIfTrueOrPop(usize),
     Return,
     Call(usize),
     DefineGlobal(usize),
@@ -29,15 +48,41 @@ pub enum Instruction {
     SetGlobal(usize),
     GetLocal(usize),
     SetLocal(usize),
+    /// Replaces the function on top of the stack with a closure holding its captured variables.
     MakeClosure,
-    MakeUpvalue(usize, usize),
     GetUpvalue(usize),
     SetUpvalue(usize),
+    /// Replaces the object on top of the stack by its property with the name at the index.
     GetProperty(usize),
+    /// Sets the property with the name at the index of the object below the value on top of the
+    /// stack, and replaces both by the value.
     SetProperty(usize),
+    /// Replaces the superclass on top of the stack by its method with the name at the index.
     GetSuper(usize),
+    /// Pops the given number of values and pushes the concatenation of their `print` forms.
+    BuildString(usize),
+    /// Pops the given number of elements and pushes a `List` holding them.
+    BuildList(usize),
+    /// Pops the given number of key and value pairs and pushes a `Map` holding them.
+    BuildMap(usize),
+    /// Calls the `get` method of the indexed object with the index.
+    GetIndex,
+    /// Calls the `set` method of the indexed object with the index and value, leaving the
+    /// value below the method's result.
+    SetIndex,
     Print,
     Halt,
+    /// Allocates a class from the declaration at the index and pushes it, closing its methods
+    /// over the variables they capture.
+    Class(usize),
+    /// Pushes the native class with the name at the index.
+    GetNativeClass(usize),
+    /// Copies the methods of the superclass on top of the stack that the class below it
+    /// doesn't define itself into that class. The superclass stays on the stack as the
+    /// class's `super` variable.
     Inherit,
-    Invoke(usize),
+    /// Calls the method with the name at the first index on the receiver below the given
+    /// number of arguments, without binding it first. The third index is the call
+    /// site's inline cache slot.
+    Invoke(usize, usize, usize),
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
-mod token;
+pub mod span;
+pub mod token;
 pub mod scanner;
+pub mod ast;
+pub mod parser;
 pub mod compiler;
+pub mod diagnostics;
 mod instruction;
 pub mod value;
 mod function;
@@ -9,6 +13,8 @@ pub mod vm;
 pub mod native_functions;
 mod class;
 mod instance;
+mod upvalue;
+mod symbol;
 
 #[cfg(test)]
 mod tests {
@@ -1,124 +1,215 @@
 use std::collections::HashMap;
 use std::io::Read;
-use std::ops::Add;
 use lazy_static::lazy_static;
 use crate::class::Class;
-use crate::frame::CallFrame;
-use crate::function::{Function, NativeFunction};
+use crate::function::NativeFunction;
 use crate::instance::Instance;
+use crate::symbol::{Interner, Symbol};
 use crate::value::Value;
-use crate::vm::{Collectable, VM};
+use crate::vm::{Collectable, RuntimeError, VM};
 lazy_static!(
     pub static ref NATIVE_FUNCTIONS: HashMap<String, NativeFunction> = {
         let mut map = HashMap::new();
-        map.insert("readln".to_string(), NativeFunction { function: readln });
-        map.insert("fetch".to_string(), NativeFunction { function: fetch });
-        map
-    };
-
-    pub static ref NATIVE_CLASSES: HashMap<String, Class> = {
-        let mut map = HashMap::new();
-        map.insert("Map".to_string(), make_map());
-        map.insert("List".to_string(), make_list());
+        map.insert("readln".to_string(), NativeFunction { function: readln, arity: Some(0) });
+        map.insert("fetch".to_string(), NativeFunction { function: fetch, arity: Some(1) });
+        map.insert("doc".to_string(), NativeFunction { function: doc, arity: Some(1) });
         map
     };
 );
 
-fn readln(_: Vec<Value>, vm: &mut VM) -> Value {
+/// The native classes Horst code can refer to by name.
+pub const NATIVE_CLASS_NAMES: [&str; 2] = ["Map", "List"];
+
+/// Creates the native classes, including the class of the iterators returned by `List` and
+/// `Map`, which isn't visible to Horst code. The VM allocates them once when it starts.
+pub(crate) fn native_classes(strings: &mut Interner) -> Vec<Class> {
+    vec![make_map(strings), make_list(strings), make_iterator(strings)]
+}
+
+fn readln(_: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut s = String::new();
-    std::io::stdin().read_line(&mut s).unwrap();
+    if let Err(error) = std::io::stdin().read_line(&mut s) {
+        return Err(vm.runtime_error(&format!("Could not read from stdin: {}", error)));
+    }
     s.pop();
-    Value::String(s)
+    Ok(Value::String(s.into()))
 }
 
-fn fetch(args: Vec<Value>, vm: &mut VM) -> Value {
+fn fetch(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut args = args;
     let url = if let Value::String(url) = args.pop().unwrap() {
         url
     } else {
-        panic!("First argument must be a string");
+        return Err(vm.runtime_error("First argument must be a string"));
     };
-    let mut res = reqwest::blocking::get(&url).unwrap();
     let mut body = String::new();
-    res.read_to_string(&mut body).unwrap();
-    Value::String(body)
+    let result = reqwest::blocking::get(&*url)
+        .map_err(|error| error.to_string())
+        .and_then(|mut res| res.read_to_string(&mut body).map_err(|error| error.to_string()));
+    if let Err(error) = result {
+        return Err(vm.runtime_error(&format!("Could not fetch {}: {}", url, error)));
+    }
+    Ok(Value::String(body.into()))
+}
+
+/// Returns the doc comment of a function, bound method or class, or `nil` if it has none.
+fn doc(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let doc = match args.last() {
+        Some(Value::Closure(closure)) => closure.function.doc.clone(),
+        Some(Value::BoundMethod(bound)) => match &bound.method {
+            Value::Closure(method) => method.function.doc.clone(),
+            _ => None,
+        },
+        Some(Value::Class(class)) => vm.get_collectable::<Class>(*class).unwrap().doc.clone(),
+        _ => return Err(vm.runtime_error("Argument must be a function or class")),
+    };
+    Ok(doc.map_or(Value::Nil, |doc| Value::String(doc.into())))
+}
+
+/// Creates a `Map` instance, as for a `{"key": value}` literal.
+pub(crate) fn new_map(entries: Vec<(Symbol, Value)>, vm: &mut VM) -> Value {
+    let entries = Value::Foreign(vm.new_collectable(Map { entries: entries.into_iter().collect() }));
+    let mut map = Instance::new(vm.native_class("Map"));
+    map.fields.insert(vm.symbols.entries.clone(), entries);
+    vm.new_instance(map)
 }
 
-fn make_map() -> Class {
+fn make_map(strings: &mut Interner) -> Class {
     let mut methods = HashMap::new();
-    methods.insert("get".to_string(), Value::Native(NativeFunction { function: map_get }));
-    methods.insert("set".to_string(), Value::Native(NativeFunction { function: map_set }));
-    methods.insert("toString".to_string(), Value::Native(NativeFunction { function: map_to_string }));
+    methods.insert(strings.intern("init"), Value::Native(NativeFunction { function: map_init, arity: Some(1) }));
+    methods.insert(strings.intern("get"), Value::Native(NativeFunction { function: map_get, arity: Some(2) }));
+    methods.insert(strings.intern("set"), Value::Native(NativeFunction { function: map_set, arity: Some(3) }));
+    methods.insert(strings.intern("toString"), Value::Native(NativeFunction { function: map_to_string, arity: Some(1) }));
+    methods.insert(strings.intern("iterator"), Value::Native(NativeFunction { function: map_iterator, arity: Some(1) }));
     Class {
         name: "Map".to_string(),
         methods,
+        doc: None,
     }
 }
 
-fn map_get(args: Vec<Value>, vm: &mut VM) -> Value {
-    let mut args = args;
-    let map = if let Value::Instance(map) = args.remove(0) {
+/// The entries of a map. They live apart from the instance's fields, so keys can't shadow the
+/// map's methods.
+struct Map {
+    entries: HashMap<Symbol, Value>,
+}
+
+impl Collectable for Map {
+    fn collect(&self) -> Vec<usize> {
+        self.entries.values().flat_map(|value| value.collect()).collect()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+/// The heap id of the entries of the map `this`.
+fn map_entries(this: Value, vm: &mut VM) -> Result<usize, RuntimeError> {
+    let entries_name = vm.symbols.entries.clone();
+    let map = if let Value::Instance(map) = this {
         vm.get_instance(map).unwrap()
     } else {
-        panic!("First argument must be a map");
+        return Err(vm.runtime_error("First argument must be a map"));
     };
-    let key = if let Value::String(key) = args.remove(0) {
-        key
+    if let Some(Value::Foreign(entries)) = map.fields.get(&entries_name) {
+        Ok(*entries)
     } else {
-        panic!("Second argument must be a string");
+        Err(vm.runtime_error("Map must have an entries field"))
+    }
+}
+
+fn map_init(mut args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let this = if let Value::Instance(this) = args.remove(0) {
+        this
+    } else {
+        return Err(vm.runtime_error("First argument must be a map"));
     };
-    map.fields.get(&key).unwrap_or(&Value::Nil).clone()
+
+    let entries = Value::Foreign(vm.new_collectable(Map { entries: HashMap::new() }));
+    let entries_name = vm.symbols.entries.clone();
+    vm.get_instance_mut(this).unwrap().fields.insert(entries_name, entries);
+    Ok(Value::Nil)
 }
 
-fn map_set(args: Vec<Value>, vm: &mut VM) -> Value {
-    println!("{:?}", args);
+fn map_get(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut args = args;
-    let mut map = if let Value::Instance(map) = args.remove(0) {
-        vm.get_instance_mut(map).unwrap()
+    let entries = map_entries(args.remove(0), vm)?;
+    let key = if let Value::String(key) = args.remove(0) {
+        vm.intern(&key)
     } else {
-        panic!("First argument must be a map");
+        return Err(vm.runtime_error("Second argument must be a string"));
     };
+    Ok(vm.get_collectable::<Map>(entries).unwrap().entries.get(&key).cloned().unwrap_or(Value::Nil))
+}
+
+fn map_set(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let mut args = args;
+    let entries = map_entries(args.remove(0), vm)?;
     let key = if let Value::String(key) = args.remove(0) {
-        key
+        vm.intern(&key)
     } else {
-        panic!("Second argument must be a string");
+        return Err(vm.runtime_error("Second argument must be a string"));
     };
     let value = args.pop().unwrap();
-    map.fields.insert(key, value);
-    Value::Nil
+    vm.get_collectable_mut::<Map>(entries).unwrap().entries.insert(key, value);
+    Ok(Value::Nil)
 }
 
-fn map_to_string(args: Vec<Value>, vm: &mut VM) -> Value {
+fn map_to_string(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut args = args;
-    let map = if let Value::Instance(map) = args.pop().unwrap() {
-        vm.get_instance(map).unwrap()
-    } else {
-        panic!("First argument must be a map");
-    };
+    let entries = map_entries(args.pop().unwrap(), vm)?;
+    let map = vm.get_collectable::<Map>(entries).unwrap();
     let mut s = "{".to_string();
-    for (i, (key, value)) in map.fields.iter().enumerate() {
+    for (i, (key, value)) in map.entries.iter().enumerate() {
         if i > 0 {
             s.push_str(", ");
         }
         if let Value::String(value) = value {
             s.push_str(&format!("{}: \"{}\"", key, value));
         } else {
-            s.push_str(&format!("{}: {}", key, value));
+            s.push_str(&format!("{}: {}", key, value.to_string(vm)));
         }
     }
     s.push('}');
-    Value::String(s)
+    Ok(Value::String(s.into()))
+}
+
+/// Iterates over the keys of a map, in sorted order.
+fn map_iterator(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let mut args = args;
+    let entries = map_entries(args.remove(0), vm)?;
+    let mut keys: Vec<Symbol> = vm.get_collectable::<Map>(entries).unwrap().entries.keys().cloned().collect();
+    keys.sort_by(|a, b| str::cmp(a, b));
+
+    let items = vm.new_collectable(List { items: keys.into_iter().map(|key| Value::String(key.into())).collect() });
+    Ok(new_iterator(items, vm))
 }
 
-fn make_list() -> Class {
+/// Creates a `List` instance, as for a `[1, 2, 3]` literal.
+pub(crate) fn new_list(items: Vec<Value>, vm: &mut VM) -> Value {
+    let items = Value::Foreign(vm.new_collectable(List { items }));
+    let mut list = Instance::new(vm.native_class("List"));
+    list.fields.insert(vm.symbols.items.clone(), items);
+    vm.new_instance(list)
+}
+
+fn make_list(strings: &mut Interner) -> Class {
     let mut methods = HashMap::new();
-    methods.insert("init".to_string(), Value::Native(NativeFunction { function: list_init }));
-    methods.insert("add".to_string(), Value::Native(NativeFunction { function: list_add }));
-    methods.insert("get".to_string(), Value::Native(NativeFunction { function: list_get }));
-    methods.insert("toString".to_string(), Value::Native(NativeFunction { function: list_to_string }));
+    methods.insert(strings.intern("init"), Value::Native(NativeFunction { function: list_init, arity: None }));
+    methods.insert(strings.intern("add"), Value::Native(NativeFunction { function: list_add, arity: Some(2) }));
+    methods.insert(strings.intern("get"), Value::Native(NativeFunction { function: list_get, arity: Some(2) }));
+    methods.insert(strings.intern("set"), Value::Native(NativeFunction { function: list_set, arity: Some(3) }));
+    methods.insert(strings.intern("toString"), Value::Native(NativeFunction { function: list_to_string, arity: Some(1) }));
+    methods.insert(strings.intern("iterator"), Value::Native(NativeFunction { function: list_iterator, arity: Some(1) }));
     Class {
         name: "List".to_string(),
         methods,
+        doc: None,
     }
 }
 
@@ -128,17 +219,7 @@ struct List {
 
 impl Collectable for List {
     fn collect(&self) -> Vec<usize> {
-        let mut ids = vec![];
-
-        for item in &self.items {
-            if let Value::Instance(id) = item {
-                ids.push(*id);
-            } else if let Value::Foreign(id) = item {
-                ids.push(*id);
-            }
-        }
-
-        ids
+        self.items.iter().flat_map(|item| item.collect()).collect()
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
@@ -150,17 +231,18 @@ impl Collectable for List {
     }
 }
 
-fn list_to_string(args: Vec<Value>, vm: &mut VM)-> Value {
+fn list_to_string(args: Vec<Value>, vm: &mut VM)-> Result<Value, RuntimeError> {
     let mut args = args;
+    let (items_name, to_string) = (vm.symbols.items.clone(), vm.symbols.to_string.clone());
     let list = if let Value::Instance(list) = args.pop().unwrap() {
         vm.get_instance(list).unwrap()
     } else {
-        panic!("First argument must be a list");
+        return Err(vm.runtime_error("First argument must be a list"));
     };
-    let list_items = if let Value::Foreign(id) = list.fields.get("items").unwrap() {
+    let list_items = if let Value::Foreign(id) = list.fields.get(&items_name).unwrap() {
         vm.get_collectable::<List>(*id).unwrap().items.clone()
     } else {
-        panic!("List must have a field called items");
+        return Err(vm.runtime_error("List must have a field called items"));
     };
     let mut s = String::new();
     s.push_str("List([");
@@ -168,27 +250,16 @@ fn list_to_string(args: Vec<Value>, vm: &mut VM)-> Value {
         match item {
             Value::Instance(id) => {
                 let instance = vm.get_instance(*id).unwrap();
-                if let Some(Value::Function(function)) = instance.class.methods.get("toString") {
-                    let call_stack = vm.call_stack.clone();
-                    vm.call_stack = vec![CallFrame {
-                        function: function.clone(),
-                        ip: 0,
-                        base_pointer: 0,
-                    }];
-                    vm.stack.push(Value::Instance(*id));
-                    let result = vm.run();
-                    vm.call_stack = call_stack;
-                    s.push_str(format!("{}", result).as_str());
-                } else if let Some(Value::Native(NativeFunction { function })) = instance.class.methods.get("toString") {
-                    let args = vec![Value::Instance(*id)];
-                    let result = function(args, vm);
-                    s.push_str(format!("{}", result).as_str());
+                let methods = &vm.get_collectable::<Class>(instance.class).unwrap().methods;
+                if let Some(method) = methods.get(&to_string).cloned() {
+                    let result = vm.call_method_now(item.clone(), method)?;
+                    s.push_str(&result.to_string(vm));
                 } else {
-                    s.push_str(format!("{}", item).as_str());
+                    s.push_str(&item.to_string(vm));
                 }
             }
             _ => {
-                s.push_str(format!("{}", item).as_str());
+                s.push_str(&item.to_string(vm));
             }
         }
 
@@ -197,41 +268,43 @@ fn list_to_string(args: Vec<Value>, vm: &mut VM)-> Value {
         }
     }
     s.push_str("])");
-    Value::String(s)
+    Ok(Value::String(s.into()))
 }
 
-fn list_init(mut args: Vec<Value>, vm: &mut VM) -> Value {
+fn list_init(mut args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let this = if let Value::Instance(this) = args.remove(0) {
         this
     } else {
-        panic!("First argument must be a list");
+        return Err(vm.runtime_error("First argument must be a list"));
     };
-    let mut list = List { items: args };
+    let list = List { items: args };
 
     // Create a Foreign value first, before getting a mutable reference to the instance
     let foreign_value = Value::Foreign(vm.new_collectable(list));
 
     // Now, we can get the mutable reference to the instance, and insert the foreign value
+    let items_name = vm.symbols.items.clone();
     let instance = vm.get_instance_mut(this).unwrap();
-    instance.fields.insert("items".to_string(), foreign_value);
+    instance.fields.insert(items_name, foreign_value);
 
-    Value::Nil
+    Ok(Value::Nil)
 }
 
-fn list_add(args: Vec<Value>, vm: &mut VM) -> Value {
+fn list_add(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut args = args;
-    let this = if let Value::Instance(this) = args.pop().unwrap() {
+    let this = if let Value::Instance(this) = args.remove(0) {
         this
     } else {
-        panic!("First argument must be a list");
+        return Err(vm.runtime_error("First argument must be a list"));
     };
 
+    let items_name = vm.symbols.items.clone();
     let items_foreign_value = {
         let instance = vm.get_instance_mut(this).unwrap();
-        if let Value::Foreign(items) = instance.fields.get("items").unwrap() {
+        if let Value::Foreign(items) = instance.fields.get(&items_name).unwrap() {
             *items
         } else {
-            panic!("List must have an items field");
+            return Err(vm.runtime_error("List must have an items field"));
         }
     };
 
@@ -240,26 +313,27 @@ fn list_add(args: Vec<Value>, vm: &mut VM) -> Value {
     let items = if let Some(items) = items.as_any_mut().downcast_mut::<List>() {
         items
     } else {
-        panic!("List must have an items field");
+        return Err(vm.runtime_error("List must have an items field"));
     };
     items.items.push(args.pop().unwrap());
-    Value::Nil
+    Ok(Value::Nil)
 }
 
-fn list_get(args: Vec<Value>, vm: &mut VM) -> Value {
+fn list_get(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
     let mut args = args;
     let this = if let Value::Instance(this) = args.remove(0) {
         this
     } else {
-        panic!("First argument must be a list");
+        return Err(vm.runtime_error("First argument must be a list"));
     };
 
+    let items_name = vm.symbols.items.clone();
     let items_foreign_value = {
         let instance = vm.get_instance_mut(this).unwrap();
-        if let Value::Foreign(items) = instance.fields.get("items").unwrap() {
+        if let Value::Foreign(items) = instance.fields.get(&items_name).unwrap() {
             *items
         } else {
-            panic!("List must have an items field");
+            return Err(vm.runtime_error("List must have an items field"));
         }
     };
 
@@ -268,16 +342,116 @@ fn list_get(args: Vec<Value>, vm: &mut VM) -> Value {
     let items = if let Some(items) = items.as_any_mut().downcast_mut::<List>() {
         items
     } else {
-        panic!("List must have an items field");
+        return Err(vm.runtime_error("List must have an items field"));
     };
     let index = if let Value::Number(index) = args.remove(0) {
-        index as usize
+        index
+    } else {
+        return Err(vm.runtime_error("Second argument must be a number"));
+    };
+    let length = items.items.len();
+    let index = list_index(index, length, vm)?;
+    Ok(vm.get_collectable::<List>(items_foreign_value).unwrap().items[index].clone())
+}
+
+fn list_set(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let mut args = args;
+    let this = if let Value::Instance(this) = args.remove(0) {
+        this
+    } else {
+        return Err(vm.runtime_error("First argument must be a list"));
+    };
+    let index = if let Value::Number(index) = args.remove(0) {
+        index
+    } else {
+        return Err(vm.runtime_error("Second argument must be a number"));
+    };
+    let value = args.remove(0);
+
+    let items_name = vm.symbols.items.clone();
+    let items = if let Some(Value::Foreign(items)) = vm.get_instance(this).unwrap().fields.get(&items_name) {
+        *items
+    } else {
+        return Err(vm.runtime_error("List must have an items field"));
+    };
+    let length = vm.get_collectable::<List>(items).unwrap().items.len();
+    let index = list_index(index, length, vm)?;
+
+    vm.get_collectable_mut::<List>(items).unwrap().items[index] = value;
+    Ok(Value::Nil)
+}
+
+/// Checks that `index` is a whole number that points into a list of `length` items.
+fn list_index(index: f64, length: usize, vm: &VM) -> Result<usize, RuntimeError> {
+    if index.fract() != 0.0 {
+        return Err(vm.runtime_error(&format!("List index {} is not an integer", index)));
+    }
+    if index < 0.0 || index as usize >= length {
+        return Err(vm.runtime_error(&format!("List index {} out of range for length {}", index, length)));
+    }
+    Ok(index as usize)
+}
+
+fn list_iterator(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let mut args = args;
+    let this = if let Value::Instance(this) = args.remove(0) {
+        this
     } else {
-        panic!("Second argument must be a number");
+        return Err(vm.runtime_error("First argument must be a list"));
     };
-    if let Some(item) = items.items.get(index) {
-        item.clone()
+
+    let items_name = vm.symbols.items.clone();
+    let items = if let Some(Value::Foreign(items)) = vm.get_instance(this).unwrap().fields.get(&items_name) {
+        *items
     } else {
-        Value::Nil
+        return Err(vm.runtime_error("List must have an items field"));
+    };
+    Ok(new_iterator(items, vm))
+}
+
+/// The iterator protocol: `done` is checked before each call to `next()`. Iterators share the
+/// items of the list they iterate over, so items added before `next()` returns the last item
+/// are visited too. Returning the last item sets `done`, so items added after that aren't.
+fn make_iterator(strings: &mut Interner) -> Class {
+    let mut methods = HashMap::new();
+    methods.insert(strings.intern("next"), Value::Native(NativeFunction { function: iterator_next, arity: Some(1) }));
+    Class {
+        name: "Iterator".to_string(),
+        methods,
+        doc: None,
     }
-}
\ No newline at end of file
+}
+
+fn new_iterator(items: usize, vm: &mut VM) -> Value {
+    let done = vm.get_collectable::<List>(items).unwrap().items.is_empty();
+    let mut iterator = Instance::new(vm.native_class("Iterator"));
+    iterator.fields.insert(vm.symbols.items.clone(), Value::Foreign(items));
+    iterator.fields.insert(vm.symbols.index.clone(), Value::Number(0.0));
+    iterator.fields.insert(vm.symbols.done.clone(), Value::Boolean(done));
+    vm.new_instance(iterator)
+}
+
+fn iterator_next(args: Vec<Value>, vm: &mut VM) -> Result<Value, RuntimeError> {
+    let mut args = args;
+    let this = if let Value::Instance(this) = args.remove(0) {
+        this
+    } else {
+        return Err(vm.runtime_error("First argument must be an iterator"));
+    };
+
+    let (items_name, index_name, done_name) = (vm.symbols.items.clone(), vm.symbols.index.clone(), vm.symbols.done.clone());
+    let iterator = vm.get_instance(this).unwrap();
+    let (items, index) = match (iterator.fields.get(&items_name), iterator.fields.get(&index_name)) {
+        (Some(Value::Foreign(items)), Some(Value::Number(index))) => (*items, *index as usize),
+        _ => return Err(vm.runtime_error("Iterator must have items and index fields")),
+    };
+
+    let items = &vm.get_collectable::<List>(items).unwrap().items;
+    let item = items.get(index).cloned().unwrap_or(Value::Nil);
+    let done = index + 1 >= items.len();
+
+    let iterator = vm.get_instance_mut(this).unwrap();
+    iterator.fields.insert(index_name, Value::Number((index + 1) as f64));
+    iterator.fields.insert(done_name, Value::Boolean(done));
+    Ok(item)
+}
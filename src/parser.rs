@@ -0,0 +1,1242 @@
+use crate::ast::{
+    BinaryOp, ClassDecl, Expr, ExprKind, FunctionDecl, Identifier, LogicalOp, Stmt, StmtKind, UnaryOp,
+};
+use std::collections::HashMap;
+use crate::compiler::CompileError;
+use crate::span::Span;
+use crate::token::{Token, TokenKind};
+
+type ParseResult<T> = Result<T, CompileError>;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    errors: Vec<CompileError>,
+    /// The text of each run of `///` comments, by the index of the token that follows it.
+    docs: HashMap<usize, String>,
+}
+
+impl Parser {
+    /// Doc comments are taken out of the token stream up front, so they can appear anywhere a
+    /// comment can. Only declarations look them up; everywhere else they are ignored.
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        let mut docs = HashMap::new();
+        let mut lines = vec![];
+        let mut kept = vec![];
+
+        for token in tokens {
+            if let TokenKind::DocComment(line) = token.kind {
+                lines.push(line);
+                continue;
+            }
+            if !lines.is_empty() {
+                docs.insert(kept.len(), lines.join("\n"));
+                lines.clear();
+            }
+            kept.push(token);
+        }
+
+        Parser {
+            tokens: kept,
+            current: 0,
+            errors: vec![],
+            docs,
+        }
+    }
+
+    /// Parses the whole token stream. Syntax errors don't stop parsing: the parser skips
+    /// ahead to the next statement boundary, so every error in the file is reported.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let mut statements = vec![];
+
+        while !self.is_at_end() {
+            statements.extend(self.declaration());
+        }
+
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+
+        Ok(statements)
+    }
+
+    fn declaration(&mut self) -> Option<Stmt> {
+        let start = self.current;
+        // Doc comments before anything but a function or class are discarded.
+        let doc = self.doc_comment();
+
+        let result = if self.match_token(TokenKind::Let) {
+            self.let_declaration()
+        } else if self.match_token(TokenKind::Fn) {
+            self.function_declaration(doc)
+        } else if self.match_token(TokenKind::Class) {
+            self.class_declaration(doc)
+        } else {
+            self.statement()
+        };
+
+        match result {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize(start);
+                None
+            }
+        }
+    }
+
+    /// Skips tokens until the start of the next statement, always making progress past `start`.
+    /// Blocks opened while skipping are skipped as a whole, and a `}` closing an enclosing block
+    /// is left for that block to consume.
+    fn synchronize(&mut self, start: usize) {
+        if self.current == start && !self.is_at_end() {
+            self.advance();
+        }
+
+        let mut depth = 0;
+
+        while !self.is_at_end() {
+            if depth == 0 && self.tokens[self.current - 1].kind == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.peek() {
+                TokenKind::LeftBrace => depth += 1,
+                TokenKind::RightBrace if depth == 0 => return,
+                TokenKind::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.advance();
+                        return;
+                    }
+                }
+                TokenKind::Let
+                | TokenKind::Fn
+                | TokenKind::Class
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::Break
+                | TokenKind::Continue
+                | TokenKind::Return
+                | TokenKind::Print if depth == 0 => return,
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    fn let_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.consume_identifier("Expect variable name.")?;
+
+        let initializer = if self.match_token(TokenKind::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.match_token(TokenKind::Semicolon);
+
+        let span = name.span;
+        Ok(Stmt {
+            kind: StmtKind::Let { name, initializer },
+            span,
+        })
+    }
+
+    fn function_declaration(&mut self, doc: Option<String>) -> ParseResult<Stmt> {
+        let name = self.consume_identifier("Expect function name.")?;
+        let span = name.span;
+        let function = self.function(Some(name), doc, span)?;
+
+        Ok(Stmt {
+            kind: StmtKind::Function(function),
+            span,
+        })
+    }
+
+    fn function(&mut self, name: Option<Identifier>, doc: Option<String>, span: Span) -> ParseResult<FunctionDecl> {
+        self.consume_token(TokenKind::LeftParen, "Expect '(' after function name.")?;
+
+        let mut params: Vec<Identifier> = vec![];
+        if !self.check(&TokenKind::RightParen) {
+            loop {
+                let param = self.consume_identifier("Expect parameter name.")?;
+
+                if params.iter().any(|other| other.name == param.name) {
+                    return Err(self.error_at_span(
+                        param.span,
+                        &format!("Cannot have two parameters with the same name: {}", param.name),
+                    ));
+                }
+
+                params.push(param);
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume_token(TokenKind::RightParen, "Expect ')' after parameters.")?;
+
+        let body = self.block()?;
+        let end = self.previous_span();
+
+        Ok(FunctionDecl {
+            name,
+            doc,
+            params,
+            body,
+            span,
+            end,
+        })
+    }
+
+    fn class_declaration(&mut self, doc: Option<String>) -> ParseResult<Stmt> {
+        let name = self.consume_identifier("Expect class name.")?;
+        let span = name.span;
+        let superclass = if self.match_token(TokenKind::Less) {
+            Some(self.consume_identifier("Expect superclass name.")?)
+        } else {
+            None
+        };
+
+        self.consume_token(TokenKind::LeftBrace, "Expect '{' before class body.")?;
+
+        let mut methods = vec![];
+        while !self.check(&TokenKind::RightBrace) && !self.is_at_end() {
+            let doc = self.doc_comment();
+            let method = self.consume_identifier("Expect function name.")?;
+            let method_span = method.span;
+            methods.push(self.function(Some(method), doc, method_span)?);
+        }
+
+        self.consume_token(TokenKind::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt {
+            kind: StmtKind::Class(ClassDecl {
+                name,
+                doc,
+                superclass,
+                methods,
+            }),
+            span,
+        })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        if self.match_token(TokenKind::Print) {
+            self.print_statement()
+        } else if self.check(&TokenKind::LeftBrace) {
+            self.block_statement()
+        } else if self.match_token(TokenKind::If) {
+            self.if_statement()
+        } else if self.match_token(TokenKind::While) {
+            self.while_statement(None)
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement(None)
+        } else if self.match_token(TokenKind::Break) {
+            self.loop_control_statement(StmtKind::Break)
+        } else if self.match_token(TokenKind::Continue) {
+            self.loop_control_statement(StmtKind::Continue)
+        } else if self.match_token(TokenKind::Return) {
+            self.return_statement()
+        } else if self.check_label() {
+            self.labeled_statement()
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.peek_span();
+        let statements = self.block()?;
+
+        Ok(Stmt {
+            kind: StmtKind::Block(statements),
+            span,
+        })
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+
+        self.consume_token(TokenKind::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume_token(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let then_branch = Box::new(self.block_statement()?);
+        let else_branch = if self.match_token(TokenKind::Else) {
+            Some(Box::new(self.block_statement()?))
+        } else {
+            None
+        };
+
+        Ok(Stmt {
+            kind: StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            },
+            span,
+        })
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+
+        let value = if self.check(&TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+
+        self.match_token(TokenKind::Semicolon);
+
+        Ok(Stmt {
+            kind: StmtKind::Return(value),
+            span,
+        })
+    }
+
+    fn check_label(&self) -> bool {
+        matches!(self.peek(), TokenKind::Identifier(_))
+            && self.tokens.get(self.current + 1).map(|token| &token.kind) == Some(&TokenKind::Colon)
+    }
+
+    fn labeled_statement(&mut self) -> ParseResult<Stmt> {
+        let label = self.consume_identifier("Expect label.")?;
+        self.consume_token(TokenKind::Colon, "Expect ':' after label.")?;
+
+        if self.match_token(TokenKind::While) {
+            self.while_statement(Some(label))
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement(Some(label))
+        } else {
+            Err(self.error_at_current("Expect loop after label.", Some("loop".to_string())))
+        }
+    }
+
+    fn loop_control_statement(&mut self, kind: fn(Option<Identifier>) -> StmtKind) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+
+        let label = if let TokenKind::Identifier(_) = self.peek() {
+            Some(self.consume_identifier("Expect label.")?)
+        } else {
+            None
+        };
+
+        self.match_token(TokenKind::Semicolon);
+
+        Ok(Stmt {
+            kind: kind(label),
+            span,
+        })
+    }
+
+    fn while_statement(&mut self, label: Option<Identifier>) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+
+        self.consume_token(TokenKind::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume_token(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let body = Box::new(self.block_statement()?);
+
+        Ok(Stmt {
+            kind: StmtKind::While { label, condition, body },
+            span,
+        })
+    }
+
+    fn for_statement(&mut self, label: Option<Identifier>) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+
+        self.consume_token(TokenKind::LeftParen, "Expect '(' after 'for'.")?;
+
+        if matches!(self.peek(), TokenKind::Identifier(_))
+            && self.tokens.get(self.current + 1).map(|token| &token.kind) == Some(&TokenKind::In)
+        {
+            let item = self.consume_identifier("Expect loop variable name.")?;
+            self.consume_token(TokenKind::In, "Expect 'in' after loop variable.")?;
+            let iterable = self.expression()?;
+            self.consume_token(TokenKind::RightParen, "Expect ')' after iterable.")?;
+            let body = Box::new(self.block_statement()?);
+
+            return Ok(Stmt {
+                kind: StmtKind::ForIn {
+                    label,
+                    item,
+                    iterable,
+                    body,
+                },
+                span,
+            });
+        }
+
+        let initializer = if self.match_token(TokenKind::Semicolon) {
+            None
+        } else if self.match_token(TokenKind::Let) {
+            Some(Box::new(self.let_declaration()?))
+        } else {
+            Some(Box::new(self.expression_statement()?))
+        };
+
+        let condition = if self.check(&TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume_token(TokenKind::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(&TokenKind::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume_token(TokenKind::RightParen, "Expect ')' after for clauses.")?;
+
+        let body = Box::new(self.block_statement()?);
+
+        Ok(Stmt {
+            kind: StmtKind::For {
+                label,
+                initializer,
+                condition,
+                increment,
+                body,
+            },
+            span,
+        })
+    }
+
+    fn print_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.previous_span();
+        let value = self.expression()?;
+
+        self.match_token(TokenKind::Semicolon);
+
+        Ok(Stmt {
+            kind: StmtKind::Print(value),
+            span,
+        })
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let expression = self.expression()?;
+
+        self.match_token(TokenKind::Semicolon);
+
+        let span = expression.span;
+        Ok(Stmt {
+            kind: StmtKind::Expression(expression),
+            span,
+        })
+    }
+
+    /// Expressions are parsed by recursive descent with one method per precedence level,
+    /// from loosest to tightest binding:
+    ///
+    /// | Level          | Operators                          | Associativity |
+    /// |----------------|------------------------------------|---------------|
+    /// | assignment     | `=` `+=` `-=` `*=` `/=` `%=`       | right         |
+    /// | or             | `or`                               | left          |
+    /// | and            | `and`                              | left          |
+    /// | equality       | `==` `!=`                          | left          |
+    /// | comparison     | `<` `<=` `>` `>=`                  | left          |
+    /// | bitwise or     | `\|`                               | left          |
+    /// | bitwise xor    | `^`                                | left          |
+    /// | bitwise and    | `&`                                | left          |
+    /// | shift          | `<<` `>>`                          | left          |
+    /// | addition       | `+` `-`                            | left          |
+    /// | multiplication | `*` `/` `%`                        | left          |
+    /// | unary          | `!` `-` `~`                        | right         |
+    /// | power          | `**`                               | right         |
+    /// | call           | `()` `.name` `[index]`             | left          |
+    ///
+    /// As in Python, the bitwise operators bind tighter than comparisons, so `x & 1 == 0`
+    /// means `(x & 1) == 0`.
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let target = self.or()?;
+
+        if self.match_token(TokenKind::Equal) {
+            let equals = self.previous_span();
+            let value = Box::new(self.assignment()?);
+
+            let kind = match target.kind {
+                ExprKind::Variable(name) => ExprKind::Assign {
+                    name: Identifier {
+                        name,
+                        span: target.span,
+                    },
+                    value,
+                },
+                ExprKind::Get { object, name } => ExprKind::Set { object, name, value },
+                ExprKind::Index { object, index } => ExprKind::SetIndex { object, index, value },
+                _ => return Err(self.error_at_span(equals, "Invalid assignment target.")),
+            };
+
+            return Ok(Expr { kind, span: equals });
+        }
+
+        let op = match self.peek() {
+            TokenKind::PlusEqual => BinaryOp::Add,
+            TokenKind::MinusEqual => BinaryOp::Subtract,
+            TokenKind::StarEqual => BinaryOp::Multiply,
+            TokenKind::SlashEqual => BinaryOp::Divide,
+            TokenKind::PercentEqual => BinaryOp::Modulo,
+            _ => return Ok(target),
+        };
+        self.advance();
+        let operator = self.previous_span();
+        let value = Box::new(self.assignment()?);
+
+        let kind = match target.kind {
+            ExprKind::Variable(name) => ExprKind::CompoundAssign {
+                name: Identifier {
+                    name,
+                    span: target.span,
+                },
+                op,
+                value,
+            },
+            ExprKind::Get { object, name } => ExprKind::CompoundSet { object, name, op, value },
+            ExprKind::Index { object, index } => ExprKind::CompoundSetIndex { object, index, op, value },
+            _ => return Err(self.error_at_span(operator, "Invalid assignment target.")),
+        };
+
+        Ok(Expr { kind, span: operator })
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expression = self.and()?;
+
+        while self.match_token(TokenKind::Or) {
+            let span = self.previous_span();
+            let right = self.and()?;
+            expression = logical(expression, LogicalOp::Or, right, span);
+        }
+
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expression = self.equality()?;
+
+        while self.match_token(TokenKind::And) {
+            let span = self.previous_span();
+            let right = self.equality()?;
+            expression = logical(expression, LogicalOp::And, right, span);
+        }
+
+        Ok(expression)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::comparison, |kind| match kind {
+            TokenKind::BangEqual => Some(BinaryOp::NotEqual),
+            TokenKind::EqualEqual => Some(BinaryOp::Equal),
+            _ => None,
+        })
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::bitwise_or, |kind| match kind {
+            TokenKind::Greater => Some(BinaryOp::Greater),
+            TokenKind::GreaterEqual => Some(BinaryOp::GreaterEqual),
+            TokenKind::Less => Some(BinaryOp::Less),
+            TokenKind::LessEqual => Some(BinaryOp::LessEqual),
+            _ => None,
+        })
+    }
+
+    fn bitwise_or(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::bitwise_xor, |kind| match kind {
+            TokenKind::Pipe => Some(BinaryOp::BitOr),
+            _ => None,
+        })
+    }
+
+    fn bitwise_xor(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::bitwise_and, |kind| match kind {
+            TokenKind::Caret => Some(BinaryOp::BitXor),
+            _ => None,
+        })
+    }
+
+    fn bitwise_and(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::shift, |kind| match kind {
+            TokenKind::Ampersand => Some(BinaryOp::BitAnd),
+            _ => None,
+        })
+    }
+
+    fn shift(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::addition, |kind| match kind {
+            TokenKind::LessLess => Some(BinaryOp::ShiftLeft),
+            TokenKind::GreaterGreater => Some(BinaryOp::ShiftRight),
+            _ => None,
+        })
+    }
+
+    fn addition(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::multiplication, |kind| match kind {
+            TokenKind::Minus => Some(BinaryOp::Subtract),
+            TokenKind::Plus => Some(BinaryOp::Add),
+            _ => None,
+        })
+    }
+
+    fn multiplication(&mut self) -> ParseResult<Expr> {
+        self.left_associative(Self::unary, |kind| match kind {
+            TokenKind::Slash => Some(BinaryOp::Divide),
+            TokenKind::Star => Some(BinaryOp::Multiply),
+            TokenKind::Percent => Some(BinaryOp::Modulo),
+            _ => None,
+        })
+    }
+
+    /// Parses one precedence level of left-associative binary operators.
+    fn left_associative(
+        &mut self,
+        operand: fn(&mut Self) -> ParseResult<Expr>,
+        operator: fn(&TokenKind) -> Option<BinaryOp>,
+    ) -> ParseResult<Expr> {
+        let mut expression = operand(self)?;
+
+        while let Some(op) = operator(self.peek()) {
+            self.advance();
+            let span = self.previous_span();
+            let right = operand(self)?;
+            expression = binary(expression, op, right, span);
+        }
+
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        let op = match self.peek() {
+            TokenKind::Bang => UnaryOp::Not,
+            TokenKind::Minus => UnaryOp::Negate,
+            TokenKind::Tilde => UnaryOp::BitNot,
+            _ => return self.power(),
+        };
+        self.advance();
+        let span = self.previous_span();
+        let operand = Box::new(self.unary()?);
+
+        Ok(Expr {
+            kind: ExprKind::Unary { op, operand },
+            span,
+        })
+    }
+
+    /// `**` is right-associative and binds tighter than a unary operator on its left, so
+    /// `-2 ** 2` is `-(2 ** 2)`, while its right operand may itself be unary, as in `2 ** -1`.
+    fn power(&mut self) -> ParseResult<Expr> {
+        let base = self.call()?;
+
+        if self.match_token(TokenKind::StarStar) {
+            let span = self.previous_span();
+            let exponent = self.unary()?;
+            return Ok(binary(base, BinaryOp::Power, exponent, span));
+        }
+
+        Ok(base)
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expression = self.primary()?;
+
+        loop {
+            match self.peek() {
+                TokenKind::LeftParen => expression = self.finish_call(expression)?,
+                TokenKind::Dot => {
+                    self.advance();
+                    let name = self.consume_identifier("Expect property name after '.'.")?;
+                    let span = name.span;
+                    expression = Expr {
+                        kind: ExprKind::Get {
+                            object: Box::new(expression),
+                            name,
+                        },
+                        span,
+                    };
+                }
+                TokenKind::LeftBracket => {
+                    self.advance();
+                    let open = self.previous_span();
+                    let index = self.expression()?;
+                    self.consume_token(TokenKind::RightBracket, "Expect ']' after index.")?;
+                    expression = Expr {
+                        kind: ExprKind::Index {
+                            object: Box::new(expression),
+                            index: Box::new(index),
+                        },
+                        span: open.to(self.previous_span()),
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        Ok(expression)
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
+        self.consume_token(TokenKind::LeftParen, "Expect '(' after function name.")?;
+        let open = self.previous_span();
+
+        let mut arguments = vec![];
+        while !self.match_token(TokenKind::RightParen) {
+            if !arguments.is_empty() {
+                self.consume_token(TokenKind::Comma, "Expect ',' after function argument.")?;
+            }
+
+            arguments.push(self.expression()?);
+        }
+
+        Ok(Expr {
+            kind: ExprKind::Call {
+                callee: Box::new(callee),
+                arguments,
+            },
+            span: open.to(self.previous_span()),
+        })
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        let span = self.peek_span();
+
+        let kind = match self.peek().clone() {
+            TokenKind::False => ExprKind::Boolean(false),
+            TokenKind::True => ExprKind::Boolean(true),
+            TokenKind::Nil => ExprKind::Nil,
+            TokenKind::Number(value) => ExprKind::Number(value),
+            TokenKind::String(value) => ExprKind::String(value),
+            TokenKind::Interpolation(_) => return self.interpolation(),
+            TokenKind::Identifier(name) => ExprKind::Variable(name),
+            TokenKind::This => ExprKind::This,
+            TokenKind::LeftParen => {
+                self.advance();
+                let expression = self.expression()?;
+                self.consume_token(TokenKind::RightParen, "Expect ')' after expression.")?;
+                return Ok(expression);
+            }
+            TokenKind::LeftBracket => {
+                self.advance();
+                let mut elements = vec![];
+                while !self.check(&TokenKind::RightBracket) {
+                    elements.push(self.expression()?);
+                    if !self.match_token(TokenKind::Comma) {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightBracket, "Expect ']' after list elements.")?;
+                return Ok(Expr {
+                    kind: ExprKind::List(elements),
+                    span,
+                });
+            }
+            TokenKind::LeftBrace => {
+                self.advance();
+                let mut entries = vec![];
+                while !self.check(&TokenKind::RightBrace) {
+                    let key = self.expression()?;
+                    self.consume_token(TokenKind::Colon, "Expect ':' after map key.")?;
+                    entries.push((key, self.expression()?));
+                    if !self.match_token(TokenKind::Comma) {
+                        break;
+                    }
+                }
+                self.consume_token(TokenKind::RightBrace, "Expect '}' after map entries.")?;
+                return Ok(Expr {
+                    kind: ExprKind::Map(entries),
+                    span,
+                });
+            }
+            TokenKind::Fn => {
+                self.advance();
+                let function = self.function(None, None, span)?;
+                return Ok(Expr {
+                    kind: ExprKind::Function(Box::new(function)),
+                    span,
+                });
+            }
+            TokenKind::Super => {
+                self.advance();
+                self.consume_token(TokenKind::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume_identifier("Expect superclass method name.")?;
+                if !self.check(&TokenKind::LeftParen) {
+                    return Err(self.error_at_current("Expect '(' after function name.", Some("'('".to_string())));
+                }
+                return Ok(Expr {
+                    kind: ExprKind::Super { method },
+                    span,
+                });
+            }
+            _ => {
+                return Err(self.error_at_current("Expect expression.", Some("expression".to_string())));
+            }
+        };
+
+        self.advance();
+        Ok(Expr { kind, span })
+    }
+
+    /// Parses the parts of an interpolated string literal into the literal segments and the
+    /// interpolated expressions, in order. Empty segments are left out.
+    fn interpolation(&mut self) -> ParseResult<Expr> {
+        let start = self.peek_span();
+        let mut parts = vec![];
+
+        loop {
+            let span = self.peek_span();
+            let (segment, done) = match self.peek().clone() {
+                TokenKind::Interpolation(segment) => (segment, false),
+                TokenKind::String(segment) => (segment, true),
+                _ => return Err(self.error_at_current("Expect '}' after interpolated expression.", Some("'}'".to_string()))),
+            };
+            self.advance();
+
+            if !segment.is_empty() {
+                parts.push(Expr {
+                    kind: ExprKind::String(segment),
+                    span,
+                });
+            }
+            if done {
+                break;
+            }
+            parts.push(self.expression()?);
+        }
+
+        Ok(Expr {
+            kind: ExprKind::Interpolation(parts),
+            span: start.to(self.previous_span()),
+        })
+    }
+
+    /// The doc comment written right before the current token, with its lines joined.
+    fn doc_comment(&mut self) -> Option<String> {
+        self.docs.remove(&self.current)
+    }
+
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = vec![];
+
+        self.consume_token(TokenKind::LeftBrace, "Expect '{' before block.")?;
+
+        while !self.check(&TokenKind::RightBrace) && !self.is_at_end() {
+            statements.extend(self.declaration());
+        }
+
+        self.consume_token(TokenKind::RightBrace, "Expect '}' after block.")?;
+
+        Ok(statements)
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek() == &TokenKind::Eof
+    }
+
+    fn advance(&mut self) {
+        self.current += 1;
+    }
+
+    fn check(&self, token: &TokenKind) -> bool {
+        self.peek() == token
+    }
+
+    fn match_token(&mut self, token: TokenKind) -> bool {
+        if self.check(&token) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.current].kind
+    }
+
+    fn peek_span(&self) -> Span {
+        self.tokens[self.current].span
+    }
+
+    fn previous_span(&self) -> Span {
+        self.tokens[self.current.saturating_sub(1)].span
+    }
+
+    fn consume_token(&mut self, token: TokenKind, message: &str) -> ParseResult<()> {
+        if self.check(&token) {
+            self.advance();
+            Ok(())
+        } else {
+            Err(self.error_at_current(message, Some(format!("'{}'", token))))
+        }
+    }
+
+    fn consume_identifier(&mut self, message: &str) -> ParseResult<Identifier> {
+        if let TokenKind::Identifier(name) = self.peek().clone() {
+            let span = self.peek_span();
+            self.advance();
+            Ok(Identifier { name, span })
+        } else {
+            Err(self.error_at_current(message, Some("identifier".to_string())))
+        }
+    }
+
+    fn error_at_current(&self, message: &str, expected: Option<String>) -> CompileError {
+        CompileError {
+            message: message.to_string(),
+            token: self.tokens[self.current].clone(),
+            expected,
+        }
+    }
+
+    fn error_at_span(&self, span: Span, message: &str) -> CompileError {
+        let index = self.tokens
+            .binary_search_by_key(&span.start, |token| token.span.start)
+            .unwrap_or(self.current);
+
+        CompileError {
+            message: message.to_string(),
+            token: self.tokens[index].clone(),
+            expected: None,
+        }
+    }
+}
+
+fn binary(left: Expr, op: BinaryOp, right: Expr, span: Span) -> Expr {
+    Expr {
+        kind: ExprKind::Binary {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+        span,
+    }
+}
+
+fn logical(left: Expr, op: LogicalOp, right: Expr, span: Span) -> Expr {
+    Expr {
+        kind: ExprKind::Logical {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        },
+        span,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scanner::Scanner;
+    use super::*;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    fn parse_errors(source: &str) -> Vec<CompileError> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        Parser::new(tokens).parse().unwrap_err()
+    }
+
+    fn expression(source: &str) -> ExprKind {
+        match parse(source).remove(0).kind {
+            StmtKind::Expression(expression) => expression.kind,
+            other => panic!("Expected expression statement, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_binary_operators_are_left_associative() {
+        let ExprKind::Binary { left, op, .. } = expression("1 + 2 - 3;") else {
+            panic!("Expected binary expression.");
+        };
+        assert_eq!(op, BinaryOp::Subtract);
+        assert!(matches!(left.kind, ExprKind::Binary { op: BinaryOp::Add, .. }));
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        let ExprKind::Binary { op, right, .. } = expression("1 | 2 ^ 3 & 4 << 5 + 6 % 7;") else {
+            panic!("Expected binary expression.");
+        };
+        assert_eq!(op, BinaryOp::BitOr);
+        let ExprKind::Binary { op: BinaryOp::BitXor, right, .. } = right.kind else {
+            panic!("Expected '^' below '|'.");
+        };
+        let ExprKind::Binary { op: BinaryOp::BitAnd, right, .. } = right.kind else {
+            panic!("Expected '&' below '^'.");
+        };
+        let ExprKind::Binary { op: BinaryOp::ShiftLeft, right, .. } = right.kind else {
+            panic!("Expected '<<' below '&'.");
+        };
+        let ExprKind::Binary { op: BinaryOp::Add, right, .. } = right.kind else {
+            panic!("Expected '+' below '<<'.");
+        };
+        assert!(matches!(right.kind, ExprKind::Binary { op: BinaryOp::Modulo, .. }));
+
+        let ExprKind::Binary { op, left, .. } = expression("x & 1 == 0;") else {
+            panic!("Expected binary expression.");
+        };
+        assert_eq!(op, BinaryOp::Equal);
+        assert!(matches!(left.kind, ExprKind::Binary { op: BinaryOp::BitAnd, .. }));
+    }
+
+    #[test]
+    fn test_power_is_right_associative_and_binds_tighter_than_unary() {
+        let ExprKind::Binary { op, right, .. } = expression("2 ** 3 ** 2;") else {
+            panic!("Expected binary expression.");
+        };
+        assert_eq!(op, BinaryOp::Power);
+        assert!(matches!(right.kind, ExprKind::Binary { op: BinaryOp::Power, .. }));
+
+        let ExprKind::Unary { op, operand } = expression("-2 ** 2;") else {
+            panic!("Expected unary expression.");
+        };
+        assert_eq!(op, UnaryOp::Negate);
+        assert!(matches!(operand.kind, ExprKind::Binary { op: BinaryOp::Power, .. }));
+
+        let ExprKind::Binary { right, .. } = expression("2 ** -1;") else {
+            panic!("Expected binary expression.");
+        };
+        assert!(matches!(right.kind, ExprKind::Unary { op: UnaryOp::Negate, .. }));
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let ExprKind::Interpolation(parts) = expression("\"Hello ${user.name}, ${count + 1}${x}\";") else {
+            panic!("Expected interpolation.");
+        };
+        let kinds: Vec<&ExprKind> = parts.iter().map(|part| &part.kind).collect();
+        assert!(matches!(kinds[..], [
+            ExprKind::String(_),
+            ExprKind::Get { .. },
+            ExprKind::String(_),
+            ExprKind::Binary { op: BinaryOp::Add, .. },
+            ExprKind::Variable(_),
+        ]));
+
+        let errors = parse_errors("\"${a b}\";");
+        assert_eq!(errors[0].message, "Expect '}' after interpolated expression.");
+    }
+
+    #[test]
+    fn test_assignment_targets() {
+        assert!(matches!(expression("x = 1;"), ExprKind::Assign { .. }));
+        assert!(matches!(expression("a.b = 1;"), ExprKind::Set { .. }));
+
+        let errors = parse_errors("a + b = 1;");
+        assert_eq!(errors[0].message, "Invalid assignment target.");
+        assert_eq!(errors[0].token.kind, TokenKind::Equal);
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        assert!(matches!(expression("x += 1;"), ExprKind::CompoundAssign { op: BinaryOp::Add, .. }));
+        assert!(matches!(expression("a.b %= 2;"), ExprKind::CompoundSet { op: BinaryOp::Modulo, .. }));
+
+        let ExprKind::CompoundAssign { value, .. } = expression("x -= y *= 2;") else {
+            panic!("Expected compound assignment.");
+        };
+        assert!(matches!(value.kind, ExprKind::CompoundAssign { op: BinaryOp::Multiply, .. }));
+
+        assert!(matches!(expression("a[0] += 1;"), ExprKind::CompoundSetIndex { op: BinaryOp::Add, .. }));
+
+        let errors = parse_errors("a + b += 1;");
+        assert_eq!(errors[0].message, "Invalid assignment target.");
+        assert_eq!(errors[0].token.kind, TokenKind::PlusEqual);
+    }
+
+    #[test]
+    fn test_declarations() {
+        let statements = parse("class B < A { init(x) { this.x = x; } }\nfn f(a, b) { return a; }");
+        let StmtKind::Class(class) = &statements[0].kind else {
+            panic!("Expected class declaration.");
+        };
+        assert_eq!(class.name.name, "B");
+        assert_eq!(class.superclass.as_ref().map(|name| name.name.as_str()), Some("A"));
+        assert_eq!(class.methods[0].params.len(), 1);
+
+        let StmtKind::Function(function) = &statements[1].kind else {
+            panic!("Expected function declaration.");
+        };
+        let params: Vec<&str> = function.params.iter().map(|param| param.name.as_str()).collect();
+        assert_eq!(params, vec!["a", "b"]);
+        assert_eq!((function.end.line, function.end.column), (2, 24));
+    }
+
+    #[test]
+    fn test_doc_comments_attach_to_declarations() {
+        let statements = parse("
+            /// Ignored before a statement.
+            let x = 1;
+            /// Adds two numbers.
+            ///
+            /// Returns their sum.
+            fn add(a, b) { return a + b; }
+            /// A counter.
+            class Counter {
+                /// Increments the count.
+                increment() {}
+                undocumented() {}
+                /// Trailing.
+            }
+        ");
+        assert_eq!(statements.len(), 3);
+        let StmtKind::Function(function) = &statements[1].kind else {
+            panic!("Expected function declaration.");
+        };
+        assert_eq!(function.doc.as_deref(), Some("Adds two numbers.\n\nReturns their sum."));
+        let StmtKind::Class(class) = &statements[2].kind else {
+            panic!("Expected class declaration.");
+        };
+        assert_eq!(class.doc.as_deref(), Some("A counter."));
+        let docs: Vec<Option<&str>> = class.methods.iter().map(|method| method.doc.as_deref()).collect();
+        assert_eq!(docs, vec![Some("Increments the count."), None]);
+
+        let errors = parse_errors("/// Dangling.\n}");
+        assert_eq!(errors[0].message, "Expect expression.");
+    }
+
+    #[test]
+    fn test_doc_comments_inside_expressions_are_ignored() {
+        let ExprKind::Map(entries) = expression("({\n/// The port.\n\"port\": 80 /// Trailing.\n});") else {
+            panic!("Expected map literal.");
+        };
+        assert_eq!(entries.len(), 1);
+
+        let statements = parse("let x = [\n/// First.\n1,\n];\n/// Still attaches.\nfn f() {}");
+        let StmtKind::Function(function) = &statements[1].kind else {
+            panic!("Expected function declaration.");
+        };
+        assert_eq!(function.doc.as_deref(), Some("Still attaches."));
+    }
+
+    #[test]
+    fn test_collection_literals_and_indexing() {
+        let ExprKind::Map(entries) = expression("({\"a\": [1, 2,], \"b\": {}});") else {
+            panic!("Expected map literal.");
+        };
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0].1.kind, ExprKind::List(elements) if elements.len() == 2));
+        assert_eq!(entries[1].1.kind, ExprKind::Map(vec![]));
+
+        let ExprKind::SetIndex { object, .. } = expression("a[0][1] = 2;") else {
+            panic!("Expected index assignment.");
+        };
+        assert!(matches!(object.kind, ExprKind::Index { .. }));
+
+        let errors = parse_errors("let m = {\"a\" 1};");
+        assert_eq!(errors[0].message, "Expect ':' after map key.");
+    }
+
+    #[test]
+    fn test_labeled_loops() {
+        let statements = parse("outer: while (true) { while (true) { break outer; continue; } }");
+        let StmtKind::While { label, body, .. } = &statements[0].kind else {
+            panic!("Expected while statement.");
+        };
+        assert_eq!(label.as_ref().map(|label| label.name.as_str()), Some("outer"));
+
+        let StmtKind::Block(statements) = &body.kind else {
+            panic!("Expected block.");
+        };
+        let StmtKind::While { body, .. } = &statements[0].kind else {
+            panic!("Expected while statement.");
+        };
+        let StmtKind::Block(statements) = &body.kind else {
+            panic!("Expected block.");
+        };
+        assert!(matches!(&statements[0].kind, StmtKind::Break(Some(label)) if label.name == "outer"));
+        assert_eq!(statements[1].kind, StmtKind::Continue(None));
+
+        let errors = parse_errors("outer: print 1;");
+        assert_eq!(errors[0].message, "Expect loop after label.");
+    }
+
+    #[test]
+    fn test_for_loops() {
+        let statements = parse("for (let i = 0; i < 3; i = i + 1) {}\nfor (;;) {}\nfor (item in items) {}");
+        assert!(matches!(
+            &statements[0].kind,
+            StmtKind::For { initializer: Some(_), condition: Some(_), increment: Some(_), .. }
+        ));
+        assert!(matches!(
+            &statements[1].kind,
+            StmtKind::For { initializer: None, condition: None, increment: None, .. }
+        ));
+        let StmtKind::ForIn { item, iterable, .. } = &statements[2].kind else {
+            panic!("Expected for-in statement.");
+        };
+        assert_eq!(item.name, "item");
+        assert_eq!(iterable.kind, ExprKind::Variable("items".to_string()));
+
+        let errors = parse_errors("for (let i = 0; i < 3) {}");
+        assert_eq!(errors[0].message, "Expect ';' after loop condition.");
+    }
+
+    #[test]
+    fn test_duplicate_parameters() {
+        let errors = parse_errors("fn f(a, a) {}");
+        assert_eq!(errors[0].message, "Cannot have two parameters with the same name: a");
+        assert_eq!(errors[0].token.span.column, 9);
+    }
+
+    #[test]
+    fn test_missing_token_error() {
+        let errors = parse_errors("if (x { print 1; }");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Expect ')' after condition.");
+        assert_eq!(errors[0].expected, Some("')'".to_string()));
+        assert_eq!(errors[0].token.kind, TokenKind::LeftBrace);
+        assert_eq!((errors[0].token.span.line, errors[0].token.span.column), (1, 7));
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let errors = parse_errors("
+            let = 1;
+            print 1 +;
+            let ok = 2;
+            fn (a) { return a; }
+            1 = 2;
+        ");
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(messages, vec![
+            "Expect variable name.",
+            "Expect expression.",
+            "Expect function name.",
+            "Invalid assignment target.",
+        ]);
+        let lines: Vec<usize> = errors.iter().map(|error| error.token.span.line).collect();
+        assert_eq!(lines, vec![2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn test_recovers_inside_blocks() {
+        let errors = parse_errors("fn f() { let x = ; return x; }\nwhile (true) { print ); }");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].token.kind, TokenKind::Semicolon);
+        assert_eq!(errors[1].token.kind, TokenKind::RightParen);
+    }
+
+    #[test]
+    fn test_error_at_end() {
+        let errors = parse_errors("print (1");
+        assert_eq!(errors[0].token.kind, TokenKind::Eof);
+        assert_eq!(errors[0].to_string(), "[1:9] Error at end: Expect ')' after expression.");
+    }
+}
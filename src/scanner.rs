@@ -1,10 +1,64 @@
-use crate::token::Token;
+use std::fmt;
+use crate::span::Span;
+use crate::token::{Token, TokenKind};
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanErrorKind {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedComment,
+    MalformedNumber(String),
+    InvalidEscape(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanError {
+    pub kind: ScanErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for ScanErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanErrorKind::UnexpectedCharacter(c) => write!(f, "Unexpected character '{}'.", c),
+            ScanErrorKind::UnterminatedString => write!(f, "Unterminated string."),
+            ScanErrorKind::UnterminatedComment => write!(f, "Unterminated block comment."),
+            ScanErrorKind::MalformedNumber(literal) => write!(f, "Malformed number literal '{}'.", literal),
+            ScanErrorKind::InvalidEscape(sequence) => write!(f, "Invalid escape sequence '{}'.", sequence),
+        }
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.span, self.kind)
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+/// Scans source text in a single forward pass. `start` and `current` are byte offsets into
+/// `source`, so every lookahead is O(1) and multi-byte UTF-8 characters are stepped over whole.
 pub struct Scanner {
     source: String,
-    pub tokens: Vec<Token>,
+    tokens: Vec<Token>,
+    errors: Vec<ScanError>,
+    /// The string literals whose `${` interpolations are currently being scanned, innermost last.
+    interpolations: Vec<Interpolation>,
     start: usize,
     current: usize,
+    line: usize,
+    column: usize,
+    start_line: usize,
+    start_column: usize,
+}
+
+struct Interpolation {
+    terminator: char,
+    /// The number of unclosed `{` inside the interpolated expression, so that only the `}`
+    /// matching the `${` resumes the string.
+    braces: usize,
+    span: Span,
 }
 
 impl Scanner {
@@ -12,18 +66,43 @@ impl Scanner {
         Scanner {
             source: source.to_string(),
             tokens: Vec::new(),
+            errors: Vec::new(),
+            interpolations: Vec::new(),
             start: 0,
             current: 0,
+            line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
-    pub fn scan_tokens(&mut self) {
+    /// Scans the whole source, collecting every lexical error instead of stopping at the first one.
+    pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Vec<ScanError>> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
             self.scan_token();
         }
 
-        self.tokens.push(Token::Eof);
+        for interpolation in std::mem::take(&mut self.interpolations) {
+            self.errors.push(ScanError {
+                kind: ScanErrorKind::UnterminatedString,
+                span: interpolation.span,
+            });
+        }
+
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+        self.add_token(TokenKind::Eof);
+
+        if self.errors.is_empty() {
+            Ok(std::mem::take(&mut self.tokens))
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
     }
 
     fn scan_token(&mut self) {
@@ -31,32 +110,88 @@ impl Scanner {
 
         match c {
             ' ' | '\r' | '\t' | '\n' => {},
-            '(' => self.add_token(Token::LeftParen),
-            ')' => self.add_token(Token::RightParen),
-            '{' => self.add_token(Token::LeftBrace),
-            '}' => self.add_token(Token::RightBrace),
-            ',' => self.add_token(Token::Comma),
-            '-' => self.add_token(Token::Minus),
-            '+' => self.add_token(Token::Plus),
-            ';' => self.add_token(Token::Semicolon),
-            '*' => self.add_token(Token::Star),
-            '.' => self.add_token(Token::Dot),
-            '!' => self.match_token('=', Token::BangEqual, Token::Bang),
-            '=' => self.match_token('=', Token::EqualEqual, Token::Equal),
-            '>' => self.match_token('=', Token::GreaterEqual, Token::Greater),
-            '<' => self.match_token('=', Token::LessEqual, Token::Less),
+            '(' => self.add_token(TokenKind::LeftParen),
+            ')' => self.add_token(TokenKind::RightParen),
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.braces += 1;
+                }
+                self.add_token(TokenKind::LeftBrace);
+            },
+            '}' => match self.interpolations.last_mut() {
+                Some(interpolation) if interpolation.braces == 0 => {
+                    let interpolation = self.interpolations.pop().unwrap();
+                    self.string(interpolation.terminator);
+                },
+                Some(interpolation) => {
+                    interpolation.braces -= 1;
+                    self.add_token(TokenKind::RightBrace);
+                },
+                None => self.add_token(TokenKind::RightBrace),
+            },
+            '[' => self.add_token(TokenKind::LeftBracket),
+            ']' => self.add_token(TokenKind::RightBracket),
+            ',' => self.add_token(TokenKind::Comma),
+            '-' => self.match_token('=', TokenKind::MinusEqual, TokenKind::Minus),
+            '+' => self.match_token('=', TokenKind::PlusEqual, TokenKind::Plus),
+            ';' => self.add_token(TokenKind::Semicolon),
+            '*' => {
+                if self.match_char('*') {
+                    self.add_token(TokenKind::StarStar);
+                } else {
+                    self.match_token('=', TokenKind::StarEqual, TokenKind::Star);
+                }
+            },
+            '&' => self.add_token(TokenKind::Ampersand),
+            '|' => self.add_token(TokenKind::Pipe),
+            '^' => self.add_token(TokenKind::Caret),
+            '~' => self.add_token(TokenKind::Tilde),
+            '%' => self.match_token('=', TokenKind::PercentEqual, TokenKind::Percent),
+            '.' => self.add_token(TokenKind::Dot),
+            ':' => self.add_token(TokenKind::Colon),
+            '!' => self.match_token('=', TokenKind::BangEqual, TokenKind::Bang),
+            '=' => self.match_token('=', TokenKind::EqualEqual, TokenKind::Equal),
+            '>' => {
+                if self.match_char('>') {
+                    self.add_token(TokenKind::GreaterGreater);
+                } else {
+                    self.match_token('=', TokenKind::GreaterEqual, TokenKind::Greater);
+                }
+            },
+            '<' => {
+                if self.match_char('<') {
+                    self.add_token(TokenKind::LessLess);
+                } else {
+                    self.match_token('=', TokenKind::LessEqual, TokenKind::Less);
+                }
+            },
             '/' => {
                 if self.match_char('/') {
-                    self.skip_comment()
+                    if self.peek() == '/' && self.peek_next() != '/' {
+                        self.doc_comment();
+                    } else {
+                        self.skip_comment();
+                    }
+                } else if self.match_char('*') {
+                    self.skip_block_comment();
                 } else {
-                    self.add_token(Token::Slash);
+                    self.match_token('=', TokenKind::SlashEqual, TokenKind::Slash);
+                }
+            },
+            '"' => {
+                if self.peek() == '"' && self.peek_next() == '"' {
+                    self.advance();
+                    self.advance();
+                    self.multiline_string();
+                } else {
+                    self.string('"');
                 }
             },
-            '"' => self.string('"'),
             '\'' => self.string('\''),
             '0'..='9' => self.number(),
+            'r' if matches!(self.peek(), '"' | '\'') => self.raw_string(),
             'a'..='z' | 'A'..='Z' | '_' => self.identifier(),
-            _ => panic!("Unexpected character {}", c),
+            _ => self.error(ScanErrorKind::UnexpectedCharacter(c)),
         }
     }
 
@@ -66,26 +201,60 @@ impl Scanner {
         }
     }
 
+    /// Scans a `///` comment into a token, so the parser can attach it to the declaration that
+    /// follows. Comments starting with four or more slashes are ordinary line comments.
+    fn doc_comment(&mut self) {
+        self.advance();
+        self.skip_comment();
+
+        let text = &self.source[self.start + 3..self.current];
+        let text = text.strip_prefix(' ').unwrap_or(text).trim_end_matches('\r').to_string();
+        self.add_token(TokenKind::DocComment(text));
+    }
+
+    /// Skips a `/* */` comment, which may contain nested block comments.
+    fn skip_block_comment(&mut self) {
+        let mut depth = 1;
+
+        while depth > 0 {
+            if self.is_at_end() {
+                self.error(ScanErrorKind::UnterminatedComment);
+                return;
+            }
+
+            if self.peek() == '/' && self.peek_next() == '*' {
+                depth += 1;
+                self.advance();
+            } else if self.peek() == '*' && self.peek_next() == '/' {
+                depth -= 1;
+                self.advance();
+            }
+            self.advance();
+        }
+    }
+
     fn string(&mut self, terminator: char) {
         let mut string = String::new();
-        let mut escape = false;
 
-        while self.peek() != terminator || escape {
-
-            assert!(!self.is_at_end(), "Unterminated string.");
+        while self.peek() != terminator {
+            if self.is_at_end() {
+                self.error(ScanErrorKind::UnterminatedString);
+                return;
+            }
 
             let c = self.advance();
 
-            if escape {
-                escape = false;
-                match c {
-                    'n' => string.push('\n'),
-                    't' => string.push('\t'),
-                    'r' => string.push('\r'),
-                    _ => string.push(c),
-                }
-            } else if c == '\\' {
-                escape = true;
+            if c == '\\' {
+                string.extend(self.escape_sequence());
+            } else if c == '$' && self.peek() == '{' {
+                self.advance();
+                self.interpolations.push(Interpolation {
+                    terminator,
+                    braces: 0,
+                    span: Span::new(self.start, self.current, self.start_line, self.start_column),
+                });
+                self.add_token(TokenKind::Interpolation(string));
+                return;
             } else {
                 string.push(c);
             }
@@ -93,24 +262,193 @@ impl Scanner {
 
         self.advance();
 
-        self.add_token(Token::String(string));
+        self.add_token(TokenKind::String(string));
+    }
+
+    /// Scans a `"""` string that may span several lines. A line break right after the opening
+    /// quotes and the line holding the closing quotes are dropped when they are blank, and the
+    /// indentation common to all non-blank lines is stripped. Escapes are decoded, but `${` is
+    /// not interpolated.
+    fn multiline_string(&mut self) {
+        // The leading whitespace and the remaining text of every line.
+        let mut lines = vec![(String::new(), String::new())];
+
+        while !self.source[self.current..].starts_with("\"\"\"") {
+            if self.is_at_end() {
+                self.error(ScanErrorKind::UnterminatedString);
+                return;
+            }
+
+            let c = self.advance();
+            let (indentation, text) = lines.last_mut().unwrap();
+
+            match c {
+                '\n' => lines.push((String::new(), String::new())),
+                '\r' if self.peek() == '\n' => {},
+                ' ' | '\t' if text.is_empty() => indentation.push(c),
+                '\\' => text.extend(self.escape_sequence()),
+                _ => text.push(c),
+            }
+        }
+
+        for _ in 0..3 {
+            self.advance();
+        }
+
+        if lines.len() > 1 && lines[0].1.is_empty() {
+            lines.remove(0);
+        }
+        if lines.len() > 1 && lines[lines.len() - 1].1.is_empty() {
+            lines.pop();
+        }
+
+        let common = lines
+            .iter()
+            .filter(|(_, text)| !text.is_empty())
+            .map(|(indentation, _)| indentation.len())
+            .min()
+            .unwrap_or(0);
+        let string = lines
+            .iter()
+            .map(|(indentation, text)| if text.is_empty() { String::new() } else { indentation[common..].to_string() + text })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        self.add_token(TokenKind::String(string));
     }
 
+    /// Scans a string after its `r` prefix, in which backslashes and `${` have no special meaning.
+    fn raw_string(&mut self) {
+        let terminator = self.advance();
+
+        while self.peek() != terminator {
+            if self.is_at_end() {
+                self.error(ScanErrorKind::UnterminatedString);
+                return;
+            }
+            self.advance();
+        }
+
+        self.advance();
+
+        let string = self.source[self.start + 2..self.current - 1].to_string();
+        self.add_token(TokenKind::String(string));
+    }
+
+    /// Decodes the escape sequence following a backslash, recording an error if it is invalid.
+    /// Unknown escapes such as `\\"` or `\\$` stand for the escaped character itself.
+    fn escape_sequence(&mut self) -> Option<char> {
+        if self.is_at_end() {
+            return None;
+        }
+
+        let (start, line, column) = (self.current - 1, self.line, self.column - 1);
+        let decoded = match self.advance() {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '0' => Some('\0'),
+            'x' => {
+                let digits = self.hex_digits(2);
+                u8::from_str_radix(&digits, 16).ok().filter(|_| digits.len() == 2).map(char::from)
+            },
+            'u' if self.match_char('{') => {
+                let digits = self.hex_digits(6);
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| self.match_char('}'))
+                    .and_then(char::from_u32)
+            },
+            'u' => None,
+            c => Some(c),
+        };
+
+        if decoded.is_none() {
+            self.errors.push(ScanError {
+                kind: ScanErrorKind::InvalidEscape(self.source[start..self.current].to_string()),
+                span: Span::new(start, self.current, line, column),
+            });
+        }
+
+        decoded
+    }
+
+    fn hex_digits(&mut self, max: usize) -> String {
+        let mut digits = String::new();
+        while digits.len() < max && self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+        digits
+    }
+
+    /// Scans a decimal literal with an optional fraction and exponent, or an integer literal
+    /// with a `0x`, `0b` or `0o` prefix. Underscores may separate digits.
     fn number(&mut self) {
-        while self.peek().is_digit(10) {
+        let radix = match (&self.source[self.start..self.current], self.peek()) {
+            ("0", 'x' | 'X') => 16,
+            ("0", 'b' | 'B') => 2,
+            ("0", 'o' | 'O') => 8,
+            _ => 10,
+        };
+
+        let value = if radix == 10 {
+            self.decimal_number()
+        } else {
+            self.advance();
+            let start = self.current;
+            self.digit_group(start, radix).then(|| {
+                self.source[start..self.current]
+                    .chars()
+                    .filter_map(|c| c.to_digit(radix))
+                    .fold(0.0, |value, digit| value * radix as f64 + digit as f64)
+            })
+        };
+
+        // A literal running into letters, like `0xFG` or `12px`, is malformed as a whole.
+        let mut valid = value.is_some();
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
             self.advance();
+            valid = false;
+        }
+
+        match value {
+            Some(value) if valid => self.add_token(TokenKind::Number(value)),
+            _ => {
+                let literal = self.source[self.start..self.current].to_string();
+                self.error(ScanErrorKind::MalformedNumber(literal));
+            }
         }
+    }
 
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+    fn decimal_number(&mut self) -> Option<f64> {
+        let mut valid = self.digit_group(self.start, 10);
+
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
+            valid &= self.digit_group(self.current, 10);
+        }
 
-            while self.peek().is_digit(10) {
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
                 self.advance();
             }
+            valid &= self.digit_group(self.current, 10);
         }
 
-        let value = self.source[self.start..self.current].parse::<f64>().unwrap();
-        self.add_token(Token::Number(value));
+        let text: String = self.source[self.start..self.current].chars().filter(|&c| c != '_').collect();
+        text.parse::<f64>().ok().filter(|_| valid)
+    }
+
+    /// Consumes digits and underscores from `start` on, and checks that they form a non-empty
+    /// group in which every underscore sits between two digits.
+    fn digit_group(&mut self, start: usize, radix: u32) -> bool {
+        while self.peek().is_digit(radix) || self.peek() == '_' {
+            self.advance();
+        }
+
+        let group = &self.source[start..self.current];
+        !group.is_empty() && !group.starts_with('_') && !group.ends_with('_') && !group.contains("__")
     }
 
     fn identifier(&mut self) {
@@ -120,28 +458,32 @@ impl Scanner {
 
         let value = &self.source[self.start..self.current];
         let token = match value {
-            "and" => Token::And,
-            "class" => Token::Class,
-            "else" => Token::Else,
-            "false" => Token::False,
-            "fn" => Token::Fn,
-            "if" => Token::If,
-            "let" => Token::Let,
-            "nil" => Token::Nil,
-            "or" => Token::Or,
-            "print" => Token::Print,
-            "return" => Token::Return,
-            "super" => Token::Super,
-            "this" => Token::This,
-            "true" => Token::True,
-            "while" => Token::While,
-            _ => Token::Identifier(value.to_string()),
+            "and" => TokenKind::And,
+            "break" => TokenKind::Break,
+            "class" => TokenKind::Class,
+            "continue" => TokenKind::Continue,
+            "else" => TokenKind::Else,
+            "false" => TokenKind::False,
+            "fn" => TokenKind::Fn,
+            "for" => TokenKind::For,
+            "if" => TokenKind::If,
+            "in" => TokenKind::In,
+            "let" => TokenKind::Let,
+            "nil" => TokenKind::Nil,
+            "or" => TokenKind::Or,
+            "print" => TokenKind::Print,
+            "return" => TokenKind::Return,
+            "super" => TokenKind::Super,
+            "this" => TokenKind::This,
+            "true" => TokenKind::True,
+            "while" => TokenKind::While,
+            _ => TokenKind::Identifier(value.to_string()),
         };
 
         self.add_token(token);
     }
 
-    fn match_token(&mut self, expected: char, token_type: Token, token_type_if_not_equal: Token) {
+    fn match_token(&mut self, expected: char, token_type: TokenKind, token_type_if_not_equal: TokenKind) {
         if self.match_char(expected) {
             self.add_token(token_type);
         } else {
@@ -150,45 +492,50 @@ impl Scanner {
     }
 
     fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source_at(self.current) != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.advance();
         true
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source_at(self.current)
-        }
-    }
-
-    fn source_at(&self, index: usize) -> char {
-        self.source.chars().nth(index).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            self.source_at(self.current + 1)
-        }
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
     }
 
     fn is_at_end(&self) -> bool {
-        self.source.chars().nth(self.current).is_none()
+        self.current >= self.source.len()
     }
 
-    fn add_token(&mut self, token: Token) {
-        self.tokens.push(token);
+    fn error(&mut self, kind: ScanErrorKind) {
+        let span = Span::new(self.start, self.current, self.start_line, self.start_column);
+        self.errors.push(ScanError { kind, span });
+    }
+
+    fn add_token(&mut self, kind: TokenKind) {
+        let span = Span::new(self.start, self.current, self.start_line, self.start_column);
+        self.tokens.push(Token::new(kind, span));
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source_at(self.current - 1)
+        let c = self.peek();
+        self.current += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        c
     }
 
 }
@@ -197,139 +544,395 @@ impl Scanner {
 mod tests {
     use super::*;
 
+    fn scan(source: &str) -> Vec<TokenKind> {
+        Scanner::new(source).scan_tokens().unwrap().into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn test_scan_spans() {
+        let tokens = Scanner::new("let x =\n  \"héllo\";").scan_tokens().unwrap();
+        let spans: Vec<(usize, usize)> = tokens.iter().map(|token| (token.span.line, token.span.column)).collect();
+        assert_eq!(spans, vec![(1, 1), (1, 5), (1, 7), (2, 3), (2, 10), (2, 11)]);
+        assert_eq!(tokens[0].span.start, 0);
+        assert_eq!(tokens[0].span.end, 3);
+    }
+
+    #[test]
+    fn test_scan_multi_byte_characters() {
+        let tokens = Scanner::new("// Grüße 👋\nlet s = \"日本語 😀\"; s").scan_tokens().unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Let,
+            TokenKind::Identifier("s".to_string()),
+            TokenKind::Equal,
+            TokenKind::String("日本語 😀".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::Identifier("s".to_string()),
+            TokenKind::Eof,
+        ]);
+
+        // Spans are byte offsets, columns count characters.
+        assert_eq!((tokens[3].span.start, tokens[3].span.end), (24, 40));
+        assert_eq!((tokens[5].span.line, tokens[5].span.column), (2, 18));
+    }
+
+    #[test]
+    fn test_scan_large_source() {
+        let source = "let value = 12.5; // ünïcödé comment\n".repeat(50_000);
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        assert_eq!(tokens.len(), 50_000 * 5 + 1);
+        assert_eq!(tokens[tokens.len() - 2].span.line, 50_000);
+    }
+
+    #[test]
+    fn test_scan_unexpected_character() {
+        let errors = Scanner::new("let x = 1 @ 2;").scan_tokens().unwrap_err();
+        assert_eq!(errors, vec![ScanError {
+            kind: ScanErrorKind::UnexpectedCharacter('@'),
+            span: Span::new(10, 11, 1, 11),
+        }]);
+    }
+
+    #[test]
+    fn test_scan_reports_all_errors() {
+        let errors = Scanner::new("@ let x = #;\nlet y = \"oops").scan_tokens().unwrap_err();
+        let kinds: Vec<ScanErrorKind> = errors.iter().map(|error| error.kind.clone()).collect();
+        assert_eq!(kinds, vec![
+            ScanErrorKind::UnexpectedCharacter('@'),
+            ScanErrorKind::UnexpectedCharacter('#'),
+            ScanErrorKind::UnterminatedString,
+        ]);
+        assert_eq!((errors[2].span.line, errors[2].span.column), (2, 9));
+    }
+
+    #[test]
+    fn test_scan_number_literals() {
+        assert_eq!(scan("0xFF 0b1010 0o755 1e-9 6.02E23 1_000_000 0xff_ff 2.5e+3 0"), vec![
+            TokenKind::Number(255.0),
+            TokenKind::Number(10.0),
+            TokenKind::Number(493.0),
+            TokenKind::Number(1e-9),
+            TokenKind::Number(6.02e23),
+            TokenKind::Number(1_000_000.0),
+            TokenKind::Number(65535.0),
+            TokenKind::Number(2500.0),
+            TokenKind::Number(0.0),
+            TokenKind::Eof,
+        ]);
+        assert_eq!(scan("1.5.abs"), vec![
+            TokenKind::Number(1.5),
+            TokenKind::Dot,
+            TokenKind::Identifier("abs".to_string()),
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_malformed_number_literals() {
+        let errors = Scanner::new("0x; 1e; 1e+; 0b102; 0xFG; 1__0; 1_; 12px; 1_.5; 0x_1;").scan_tokens().unwrap_err();
+        let literals: Vec<String> = errors.iter().map(|error| error.kind.to_string()).collect();
+        assert_eq!(literals, vec![
+            "Malformed number literal '0x'.",
+            "Malformed number literal '1e'.",
+            "Malformed number literal '1e+'.",
+            "Malformed number literal '0b102'.",
+            "Malformed number literal '0xFG'.",
+            "Malformed number literal '1__0'.",
+            "Malformed number literal '1_'.",
+            "Malformed number literal '12px'.",
+            "Malformed number literal '1_.5'.",
+            "Malformed number literal '0x_1'.",
+        ]);
+        assert_eq!(errors[1].span, Span::new(4, 6, 1, 5));
+    }
+
+    #[test]
+    fn test_scan_interpolation() {
+        assert_eq!(scan("\"a ${ {\"k\": 1}[\"k\"] } b ${\"${x}\"}\\${c}\""), vec![
+            TokenKind::Interpolation("a ".to_string()),
+            TokenKind::LeftBrace,
+            TokenKind::String("k".to_string()),
+            TokenKind::Colon,
+            TokenKind::Number(1.0),
+            TokenKind::RightBrace,
+            TokenKind::LeftBracket,
+            TokenKind::String("k".to_string()),
+            TokenKind::RightBracket,
+            TokenKind::Interpolation(" b ".to_string()),
+            TokenKind::Interpolation("".to_string()),
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::String("".to_string()),
+            TokenKind::String("${c}".to_string()),
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_unterminated_interpolation() {
+        let errors = Scanner::new("let s = \"a ${b;").scan_tokens().unwrap_err();
+        assert_eq!(errors, vec![ScanError {
+            kind: ScanErrorKind::UnterminatedString,
+            span: Span::new(8, 13, 1, 9),
+        }]);
+    }
+
+    #[test]
+    fn test_scan_block_and_doc_comments() {
+        assert_eq!(scan("1 /* outer /* inner */ still\n comment */ + 2 /**/;\n/// Doc line\n///\n//// plain\nfn"), vec![
+            TokenKind::Number(1.0),
+            TokenKind::Plus,
+            TokenKind::Number(2.0),
+            TokenKind::Semicolon,
+            TokenKind::DocComment("Doc line".to_string()),
+            TokenKind::DocComment("".to_string()),
+            TokenKind::Fn,
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_unterminated_block_comment() {
+        let errors = Scanner::new("let x;\n/* a /* b */").scan_tokens().unwrap_err();
+        assert_eq!(errors, vec![ScanError {
+            kind: ScanErrorKind::UnterminatedComment,
+            span: Span::new(7, 19, 2, 1),
+        }]);
+    }
+
     #[test]
     fn test_scan_identifier() {
-        let mut scanner = Scanner::new("foo");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![Token::Identifier("foo".to_string()), Token::Eof,]);
+        assert_eq!(scan("foo"), vec![TokenKind::Identifier("foo".to_string()), TokenKind::Eof,]);
     }
 
     #[test]
     fn test_scan_number() {
-        let mut scanner = Scanner::new("123");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![Token::Number(123.0), Token::Eof,]);
+        assert_eq!(scan("123"), vec![TokenKind::Number(123.0), TokenKind::Eof,]);
     }
 
     #[test]
     fn test_scan_string() {
-        let mut scanner = Scanner::new("\"foo\";");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![Token::String("foo".to_string()), Token::Semicolon, Token::Eof,]);
+        assert_eq!(scan("\"foo\";"), vec![TokenKind::String("foo".to_string()), TokenKind::Semicolon, TokenKind::Eof,]);
     }
 
     #[test]
     fn test_scan_string_with_escaped_characters() {
-        let mut scanner = Scanner::new("\"\\\"\\n\\r\\t\"");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![Token::String("\"\n\r\t".to_string()), Token::Eof,]);
+        assert_eq!(scan("\"\\\"\\n\\r\\t\""), vec![TokenKind::String("\"\n\r\t".to_string()), TokenKind::Eof,]);
+    }
+
+    #[test]
+    fn test_scan_unicode_hex_and_null_escapes() {
+        assert_eq!(scan(r#""\u{1F600} \u{e9}\x41\x7e\0""#), vec![
+            TokenKind::String("\u{1F600} \u{e9}A~\0".to_string()),
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_invalid_escapes() {
+        let errors = Scanner::new(r#""\u{110000} \u{zz} \x4 \u41 ok""#).scan_tokens().unwrap_err();
+        let kinds: Vec<ScanErrorKind> = errors.iter().map(|error| error.kind.clone()).collect();
+        assert_eq!(kinds, vec![
+            ScanErrorKind::InvalidEscape("\\u{110000}".to_string()),
+            ScanErrorKind::InvalidEscape("\\u{".to_string()),
+            ScanErrorKind::InvalidEscape("\\x4".to_string()),
+            ScanErrorKind::InvalidEscape("\\u".to_string()),
+        ]);
+        assert_eq!(errors[0].span, Span::new(1, 11, 1, 2));
+    }
+
+    #[test]
+    fn test_scan_multiline_string_strips_common_indentation() {
+        let source = "let query = \"\"\"\n        SELECT *\n          FROM users\n\n        WHERE id = \"1\"\\t\n        \"\"\";";
+        assert_eq!(scan(source)[3], TokenKind::String("SELECT *\n  FROM users\n\nWHERE id = \"1\"\t".to_string()));
+        assert_eq!(scan("\"\"\"one ${line}\"\"\""), vec![
+            TokenKind::String("one ${line}".to_string()),
+            TokenKind::Eof,
+        ]);
+
+        let errors = Scanner::new("\"\"\"\nnever closed\"\"").scan_tokens().unwrap_err();
+        assert_eq!(errors[0].kind, ScanErrorKind::UnterminatedString);
+    }
+
+    #[test]
+    fn test_scan_raw_string() {
+        assert_eq!(scan(r#"r"\d+\.${x}" r'say "hi"' r"#), vec![
+            TokenKind::String(r"\d+\.${x}".to_string()),
+            TokenKind::String(r#"say "hi""#.to_string()),
+            TokenKind::Identifier("r".to_string()),
+            TokenKind::Eof,
+        ]);
     }
 
     #[test]
     fn scan_expression() {
-        let mut scanner = Scanner::new("1 + 2");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![
-            Token::Number(1.0),
-            Token::Plus,
-            Token::Number(2.0),
-            Token::Eof,
+        assert_eq!(scan("1 + 2"), vec![
+            TokenKind::Number(1.0),
+            TokenKind::Plus,
+            TokenKind::Number(2.0),
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn scan_expression_with_multiple_operators() {
-        let mut scanner = Scanner::new("1 + 2 * 3 - 4 / 5");
-        scanner.scan_tokens();
-        assert_eq!(scanner.tokens, vec![
-            Token::Number(1.0),
-            Token::Plus,
-            Token::Number(2.0),
-            Token::Star,
-            Token::Number(3.0),
-            Token::Minus,
-            Token::Number(4.0),
-            Token::Slash,
-            Token::Number(5.0),
-            Token::Eof,
+        assert_eq!(scan("1 + 2 * 3 - 4 / 5"), vec![
+            TokenKind::Number(1.0),
+            TokenKind::Plus,
+            TokenKind::Number(2.0),
+            TokenKind::Star,
+            TokenKind::Number(3.0),
+            TokenKind::Minus,
+            TokenKind::Number(4.0),
+            TokenKind::Slash,
+            TokenKind::Number(5.0),
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn test_scan_keywords() {
-        let mut scanner = Scanner::new("and else false fn if let nil or print return true while");
-        scanner.scan_tokens();
-
-        assert_eq!(scanner.tokens, vec![
-            Token::And,
-            Token::Else,
-            Token::False,
-            Token::Fn,
-            Token::If,
-            Token::Let,
-            Token::Nil,
-            Token::Or,
-            Token::Print,
-            Token::Return,
-            Token::True,
-            Token::While,
-            Token::Eof,
+        assert_eq!(scan("and else false fn for if in let nil or print return true while"), vec![
+            TokenKind::And,
+            TokenKind::Else,
+            TokenKind::False,
+            TokenKind::Fn,
+            TokenKind::For,
+            TokenKind::If,
+            TokenKind::In,
+            TokenKind::Let,
+            TokenKind::Nil,
+            TokenKind::Or,
+            TokenKind::Print,
+            TokenKind::Return,
+            TokenKind::True,
+            TokenKind::While,
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_compound_assignment() {
+        assert_eq!(scan("+= -= *= /= %= + - * / %"), vec![
+            TokenKind::PlusEqual,
+            TokenKind::MinusEqual,
+            TokenKind::StarEqual,
+            TokenKind::SlashEqual,
+            TokenKind::PercentEqual,
+            TokenKind::Plus,
+            TokenKind::Minus,
+            TokenKind::Star,
+            TokenKind::Slash,
+            TokenKind::Percent,
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_arithmetic_and_bitwise_operators() {
+        assert_eq!(scan("** & | ^ ~ << >> <= >="), vec![
+            TokenKind::StarStar,
+            TokenKind::Ampersand,
+            TokenKind::Pipe,
+            TokenKind::Caret,
+            TokenKind::Tilde,
+            TokenKind::LessLess,
+            TokenKind::GreaterGreater,
+            TokenKind::LessEqual,
+            TokenKind::GreaterEqual,
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_comments_after_operands() {
+        assert_eq!(scan("if (x > 1) // greater\n{ main // entry\n}\n2 // two"), vec![
+            TokenKind::If,
+            TokenKind::LeftParen,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Greater,
+            TokenKind::Number(1.0),
+            TokenKind::RightParen,
+            TokenKind::LeftBrace,
+            TokenKind::Identifier("main".to_string()),
+            TokenKind::RightBrace,
+            TokenKind::Number(2.0),
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_collection_literals() {
+        assert_eq!(scan("[1]{\"a\": 2}"), vec![
+            TokenKind::LeftBracket,
+            TokenKind::Number(1.0),
+            TokenKind::RightBracket,
+            TokenKind::LeftBrace,
+            TokenKind::String("a".to_string()),
+            TokenKind::Colon,
+            TokenKind::Number(2.0),
+            TokenKind::RightBrace,
+            TokenKind::Eof,
+        ]);
+    }
+
+    #[test]
+    fn test_scan_loop_control() {
+        assert_eq!(scan("outer: break outer; continue;"), vec![
+            TokenKind::Identifier("outer".to_string()),
+            TokenKind::Colon,
+            TokenKind::Break,
+            TokenKind::Identifier("outer".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::Continue,
+            TokenKind::Semicolon,
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn test_scan_multiple_tokens() {
-        let mut scanner = Scanner::new("let five = 5;");
-        scanner.scan_tokens();
-
-        assert_eq!(scanner.tokens, vec![
-            Token::Let,
-            Token::Identifier("five".to_string()),
-            Token::Equal,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::Eof,
+        assert_eq!(scan("let five = 5;"), vec![
+            TokenKind::Let,
+            TokenKind::Identifier("five".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn test_scan_multiple_tokens_with_comments() {
-        let mut scanner = Scanner::new("let five = 5; // comment");
-        scanner.scan_tokens();
-
-        assert_eq!(scanner.tokens, vec![
-            Token::Let,
-            Token::Identifier("five".to_string()),
-            Token::Equal,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::Eof,
+        assert_eq!(scan("let five = 5; // comment"), vec![
+            TokenKind::Let,
+            TokenKind::Identifier("five".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn test_scan_multiple_tokens_with_comments_and_multiple_lines() {
-        let mut scanner = Scanner::new("let five = 5; // comment\nlet ten = 10;");
-        scanner.scan_tokens();
-
-        assert_eq!(scanner.tokens, vec![
-            Token::Let,
-            Token::Identifier("five".to_string()),
-            Token::Equal,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::Let,
-            Token::Identifier("ten".to_string()),
-            Token::Equal,
-            Token::Number(10.0),
-            Token::Semicolon,
-            Token::Eof,
+        assert_eq!(scan("let five = 5; // comment\nlet ten = 10;"), vec![
+            TokenKind::Let,
+            TokenKind::Identifier("five".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::Let,
+            TokenKind::Identifier("ten".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(10.0),
+            TokenKind::Semicolon,
+            TokenKind::Eof,
         ]);
     }
 
     #[test]
     fn test_scan_example_program() {
-        let mut scanner = Scanner::new("
+        assert_eq!(scan("
             let five = 5;
             let ten = 10;
 
@@ -338,7 +941,7 @@ mod tests {
             };
 
             let result = add(five, ten);
-            !-/*5;
+            !-/ *5;
             5 < 10 > 5;
 
             if (5 < 10) {
@@ -349,84 +952,81 @@ mod tests {
 
             10 == 10;
             10 != 9;
-        ");
-        scanner.scan_tokens();
-
-        assert_eq!(scanner.tokens, vec![
-            Token::Let,
-            Token::Identifier("five".to_string()),
-            Token::Equal,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::Let,
-            Token::Identifier("ten".to_string()),
-            Token::Equal,
-            Token::Number(10.0),
-            Token::Semicolon,
-            Token::Let,
-            Token::Identifier("add".to_string()),
-            Token::Equal,
-            Token::Fn,
-            Token::LeftParen,
-            Token::Identifier("x".to_string()),
-            Token::Comma,
-            Token::Identifier("y".to_string()),
-            Token::RightParen,
-            Token::LeftBrace,
-            Token::Identifier("x".to_string()),
-            Token::Plus,
-            Token::Identifier("y".to_string()),
-            Token::Semicolon,
-            Token::RightBrace,
-            Token::Semicolon,
-            Token::Let,
-            Token::Identifier("result".to_string()),
-            Token::Equal,
-            Token::Identifier("add".to_string()),
-            Token::LeftParen,
-            Token::Identifier("five".to_string()),
-            Token::Comma,
-            Token::Identifier("ten".to_string()),
-            Token::RightParen,
-            Token::Semicolon,
-            Token::Bang,
-            Token::Minus,
-            Token::Slash,
-            Token::Star,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::Number(5.0),
-            Token::Less,
-            Token::Number(10.0),
-            Token::Greater,
-            Token::Number(5.0),
-            Token::Semicolon,
-            Token::If,
-            Token::LeftParen,
-            Token::Number(5.0),
-            Token::Less,
-            Token::Number(10.0),
-            Token::RightParen,
-            Token::LeftBrace,
-            Token::Return,
-            Token::True,
-            Token::Semicolon,
-            Token::RightBrace,
-            Token::Else,
-            Token::LeftBrace,
-            Token::Return,
-            Token::False,
-            Token::Semicolon,
-            Token::RightBrace,
-            Token::Number(10.0),
-            Token::EqualEqual,
-            Token::Number(10.0),
-            Token::Semicolon,
-            Token::Number(10.0),
-            Token::BangEqual,
-            Token::Number(9.0),
-            Token::Semicolon,
-            Token::Eof,
+        "), vec![
+            TokenKind::Let,
+            TokenKind::Identifier("five".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::Let,
+            TokenKind::Identifier("ten".to_string()),
+            TokenKind::Equal,
+            TokenKind::Number(10.0),
+            TokenKind::Semicolon,
+            TokenKind::Let,
+            TokenKind::Identifier("add".to_string()),
+            TokenKind::Equal,
+            TokenKind::Fn,
+            TokenKind::LeftParen,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Comma,
+            TokenKind::Identifier("y".to_string()),
+            TokenKind::RightParen,
+            TokenKind::LeftBrace,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Plus,
+            TokenKind::Identifier("y".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::RightBrace,
+            TokenKind::Semicolon,
+            TokenKind::Let,
+            TokenKind::Identifier("result".to_string()),
+            TokenKind::Equal,
+            TokenKind::Identifier("add".to_string()),
+            TokenKind::LeftParen,
+            TokenKind::Identifier("five".to_string()),
+            TokenKind::Comma,
+            TokenKind::Identifier("ten".to_string()),
+            TokenKind::RightParen,
+            TokenKind::Semicolon,
+            TokenKind::Bang,
+            TokenKind::Minus,
+            TokenKind::Slash,
+            TokenKind::Star,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::Number(5.0),
+            TokenKind::Less,
+            TokenKind::Number(10.0),
+            TokenKind::Greater,
+            TokenKind::Number(5.0),
+            TokenKind::Semicolon,
+            TokenKind::If,
+            TokenKind::LeftParen,
+            TokenKind::Number(5.0),
+            TokenKind::Less,
+            TokenKind::Number(10.0),
+            TokenKind::RightParen,
+            TokenKind::LeftBrace,
+            TokenKind::Return,
+            TokenKind::True,
+            TokenKind::Semicolon,
+            TokenKind::RightBrace,
+            TokenKind::Else,
+            TokenKind::LeftBrace,
+            TokenKind::Return,
+            TokenKind::False,
+            TokenKind::Semicolon,
+            TokenKind::RightBrace,
+            TokenKind::Number(10.0),
+            TokenKind::EqualEqual,
+            TokenKind::Number(10.0),
+            TokenKind::Semicolon,
+            TokenKind::Number(10.0),
+            TokenKind::BangEqual,
+            TokenKind::Number(9.0),
+            TokenKind::Semicolon,
+            TokenKind::Eof,
         ]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// An interned string. Symbols from the same `Interner` are equal exactly when they share
+/// their allocation, so comparing and hashing them never looks at the characters.
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).cast::<u8>().hash(state);
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Symbol> for Rc<str> {
+    fn from(symbol: Symbol) -> Rc<str> {
+        symbol.0
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// The string table. The compiler interns names and string literals, and the VM keeps the
+/// table to intern the names it only learns at runtime, like map keys. The table holds on to
+/// every string it hands out until `prune` drops the ones no symbol or value refers to.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    /// Returns the symbol for `string`, adding it to the table if it isn't there yet.
+    pub fn intern(&mut self, string: &str) -> Symbol {
+        if let Some(interned) = self.strings.get(string) {
+            return Symbol(interned.clone());
+        }
+
+        let interned: Rc<str> = Rc::from(string);
+        self.strings.insert(interned.clone());
+        Symbol(interned)
+    }
+
+    /// Drops the strings only the table itself still refers to. Interning one of them again
+    /// allocates a new symbol, which is fine as no old one is left to compare it with.
+    pub fn prune(&mut self) {
+        self.strings.retain(|string| Rc::strong_count(string) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning_shares_allocations() {
+        let mut interner = Interner::default();
+        let first = interner.intern("name");
+        let second = interner.intern(&String::from("name"));
+        let other = interner.intern("other");
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(&*first, "name");
+
+        // Equal characters alone don't make equal symbols.
+        let mut separate = Interner::default();
+        assert_ne!(first, separate.intern("name"));
+    }
+
+    #[test]
+    fn test_prune_drops_unreferenced_strings() {
+        let mut interner = Interner::default();
+        let kept = interner.intern("kept");
+        let value: Rc<str> = interner.intern("value").into();
+        interner.intern("dropped");
+
+        interner.prune();
+        let mut remaining: Vec<&str> = interner.strings.iter().map(|string| &**string).collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["kept", "value"]);
+        assert_eq!(interner.intern("kept"), kept);
+        assert_eq!(&*value, "value");
+    }
+}
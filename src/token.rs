@@ -1,17 +1,31 @@
+use std::fmt;
+use crate::span::Span;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn new(kind: TokenKind, span: Span) -> Token {
+        Token { kind, span }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
-pub enum Token {
+pub enum TokenKind {
     // One character tokens
     LeftParen,              // "("
     RightParen,             // ")"
     LeftBrace,              // "{"
     RightBrace,             // "}"
+    LeftBracket,            // "["
+    RightBracket,           // "]"
     Comma,                  // ","
-    Minus,                  // "-"
-    Plus,                   // "+"
     Semicolon,              // ";"
-    Slash,                  // "/"
-    Star,                   // "*"
     Dot,                    // "."
+    Colon,                  // ":"
 
     // One or two character tokens
     Bang,                   // "!"
@@ -22,19 +36,45 @@ pub enum Token {
     GreaterEqual,           // ">="
     Less,                   // "<"
     LessEqual,              // "<="
+    Minus,                  // "-"
+    MinusEqual,             // "-="
+    Plus,                   // "+"
+    PlusEqual,              // "+="
+    Slash,                  // "/"
+    SlashEqual,             // "/="
+    Star,                   // "*"
+    StarEqual,              // "*="
+    Percent,                // "%"
+    PercentEqual,           // "%="
+    StarStar,               // "**"
+    Ampersand,              // "&"
+    Pipe,                   // "|"
+    Caret,                  // "^"
+    Tilde,                  // "~"
+    LessLess,               // "<<"
+    GreaterGreater,         // ">>"
 
     // Literals
     Identifier(String),
     String(String),
+    /// The part of a string literal before a `${`. The interpolated expression's tokens follow,
+    /// then further `Interpolation` parts, and finally a `String` with the rest of the literal.
+    Interpolation(String),
     Number(f64),
+    /// The text of a `///` comment, without the slashes and a single following space.
+    DocComment(String),
 
     // Keywords
     And,                    // "and"
+    Break,                  // "break"
     Class,                  // "class"
+    Continue,               // "continue"
     Else,                   // "else"
     False,                  // "false"
     Fn,                     // "fn"
+    For,                    // "for"
     If,                     // "if"
+    In,                     // "in"
     Let,                    // "let"
     Nil,                    // "nil"
     Or,                     // "or"
@@ -47,4 +87,72 @@ pub enum Token {
 
     // End of file
     Eof,
-}
\ No newline at end of file
+}
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lexeme = match self {
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::LeftBracket => "[",
+            TokenKind::RightBracket => "]",
+            TokenKind::Comma => ",",
+            TokenKind::Minus => "-",
+            TokenKind::Plus => "+",
+            TokenKind::Semicolon => ";",
+            TokenKind::Slash => "/",
+            TokenKind::Star => "*",
+            TokenKind::Dot => ".",
+            TokenKind::Colon => ":",
+            TokenKind::Bang => "!",
+            TokenKind::BangEqual => "!=",
+            TokenKind::Equal => "=",
+            TokenKind::EqualEqual => "==",
+            TokenKind::Greater => ">",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::Less => "<",
+            TokenKind::LessEqual => "<=",
+            TokenKind::MinusEqual => "-=",
+            TokenKind::PlusEqual => "+=",
+            TokenKind::SlashEqual => "/=",
+            TokenKind::StarEqual => "*=",
+            TokenKind::Percent => "%",
+            TokenKind::PercentEqual => "%=",
+            TokenKind::StarStar => "**",
+            TokenKind::Ampersand => "&",
+            TokenKind::Pipe => "|",
+            TokenKind::Caret => "^",
+            TokenKind::Tilde => "~",
+            TokenKind::LessLess => "<<",
+            TokenKind::GreaterGreater => ">>",
+            TokenKind::Identifier(name) => return write!(f, "{}", name),
+            TokenKind::String(value) => return write!(f, "\"{}\"", value),
+            TokenKind::Interpolation(value) => return write!(f, "\"{}${{", value),
+            TokenKind::DocComment(text) => return write!(f, "/// {}", text),
+            TokenKind::Number(value) => return write!(f, "{}", value),
+            TokenKind::And => "and",
+            TokenKind::Break => "break",
+            TokenKind::Class => "class",
+            TokenKind::Continue => "continue",
+            TokenKind::Else => "else",
+            TokenKind::False => "false",
+            TokenKind::Fn => "fn",
+            TokenKind::For => "for",
+            TokenKind::If => "if",
+            TokenKind::In => "in",
+            TokenKind::Let => "let",
+            TokenKind::Nil => "nil",
+            TokenKind::Or => "or",
+            TokenKind::Print => "print",
+            TokenKind::Return => "return",
+            TokenKind::Super => "super",
+            TokenKind::This => "this",
+            TokenKind::True => "true",
+            TokenKind::While => "while",
+            TokenKind::Eof => "end of file",
+        };
+
+        write!(f, "{}", lexeme)
+    }
+}
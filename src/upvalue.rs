@@ -0,0 +1,30 @@
+use std::any::Any;
+use crate::value::Value;
+use crate::vm::Collectable;
+
+/// A variable captured by a closure. While the variable's scope is running the upvalue is open
+/// and refers to its stack slot, so the function declaring it and every closure capturing it
+/// share the same variable. When the slot is popped the upvalue is closed and holds the value.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Upvalue {
+    /// The absolute index of the captured stack slot.
+    Open(usize),
+    Closed(Value),
+}
+
+impl Collectable for Upvalue {
+    fn collect(&self) -> Vec<usize> {
+        match self {
+            Upvalue::Open(_) => vec![],
+            Upvalue::Closed(value) => value.collect(),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -1,23 +1,46 @@
 use std::fmt;
 use std::any::{ Any };
-use crate::class::Class;
-use crate::function::{Function, NativeFunction};
-use crate::instance::Instance;
+use std::rc::Rc;
+use crate::function::{BoundMethod, Closure, Function, NativeFunction};
 use crate::vm::{Collectable, VM};
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub enum Value {
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Boolean(bool),
     Nil,
-    Function(Function),
+    /// A function prototype, turned into a closure by `MakeClosure`.
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
     Native(NativeFunction),
-    Class(Class),
+    BoundMethod(Box<BoundMethod>),
+    /// The heap id of a class.
+    Class(usize),
     Instance(usize),
     Foreign(usize),
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            // Functions are only equal to themselves, so comparing them never walks their code.
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => a == b,
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => a == b,
+            (Value::Instance(a), Value::Instance(b)) => a == b,
+            (Value::Foreign(a), Value::Foreign(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
 impl Value {
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Boolean(false))
@@ -28,24 +51,13 @@ impl Value {
     }
 
     pub fn to_string(&self, vm: &VM) -> String {
-        return match self {
-            Value::Number(n) => format!("{}", n),
-            Value::String(s) => format!("{}", s),
-            Value::Boolean(b) => format!("{}", b),
-            Value::Nil => format!("nil"),
-            Value::Function(_) => format!("<function>"),
-            Value::Native(_) => format!("<native fn>"),
-            Value::Class(c) => format!("class {}", c.name),
-            Value::Instance(i) => format!("<class instance #{}>", i),
-            Value::Foreign(f) => if let Some(foreign) = vm.heap.get(f) {
-                if let Some(to_string) = foreign.to_string(vm) {
-                    format!("{}", to_string)
-                } else {
-                    format!("<foreign>")
-                }
-            } else {
-                format!("<foreign>")
-            },
+        match self {
+            Value::Class(id) | Value::Foreign(id) => vm
+                .heap
+                .get(id)
+                .and_then(|collectable| collectable.to_string(vm))
+                .unwrap_or_else(|| format!("{}", self)),
+            _ => format!("{}", self),
         }
     }
 }
@@ -57,9 +69,10 @@ impl fmt::Display for Value {
             Value::String(s) => write!(f, "{}", s),
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
-            Value::Function(_) => write!(f, "<function>"),
+            Value::Function(_) | Value::Closure(_) => write!(f, "<function>"),
             Value::Native(_) => write!(f, "<native fn>"),
-            Value::Class(c) => write!(f, "class {}", c.name),
+            Value::BoundMethod(_) => write!(f, "<bound method>"),
+            Value::Class(_) => write!(f, "<class>"),
             Value::Instance(i) => write!(f, "<class instance #{}>", i),
             Value::Foreign(_) => write!(f, "<foreign>"),
         }
@@ -70,7 +83,10 @@ impl Collectable for Value {
     fn collect(&self) -> Vec<usize> {
         match self {
             Value::Instance(id) => vec![*id],
+            Value::Class(id) => vec![*id],
             Value::Foreign(id) => vec![*id],
+            Value::Closure(closure) => closure.upvalues.clone(),
+            Value::BoundMethod(bound) => [bound.receiver.collect(), bound.method.collect()].concat(),
             _ => vec![],
         }
     }
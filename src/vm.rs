@@ -1,24 +1,111 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
 use crate::class::Class;
 use crate::compiler::Program;
 use crate::frame::CallFrame;
-use crate::function::Function;
+use crate::function::{BoundMethod, Capture, Closure, Function};
 use crate::instance::Instance;
 use crate::instruction::Instruction;
+use crate::native_functions;
+use crate::span::Span;
+use crate::symbol::{Interner, Symbol};
+use crate::upvalue::Upvalue;
 use crate::value::Value;
 use core::any::Any;
-use std::cell::RefCell;
-use std::rc::Rc;
 
 type Heap = HashMap<usize, Box<dyn Collectable>>;
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The Horst call stack at the time of the error, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    /// The location of the instruction that failed.
+    pub fn span(&self) -> Option<Span> {
+        self.trace.first().map(|frame| frame.span)
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n  at {} ({})", frame.function, frame.span)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
 pub struct VM {
     pub(crate) call_stack: Vec<CallFrame>,
     pub(crate) stack: Vec<Value>,
     globals: Vec<Option<Value>>,
+    global_names: Vec<String>,
     constants: Vec<Value>,
+    /// The class declarations of the program, copied onto the heap when executed.
+    classes: Vec<Class>,
+    /// The heap ids of the native classes, by name.
+    native_classes: HashMap<String, usize>,
+    /// The property, method and native class names instructions refer to by index.
+    names: Vec<Symbol>,
+    /// The string table, shared with the compiler that produced the program.
+    strings: Interner,
+    pub(crate) symbols: Symbols,
     pub(crate) heap: Heap,
     next_id: usize,
+    /// The heap ids of the upvalues still referring to stack slots.
+    open_upvalues: Vec<usize>,
+    /// The method each `Invoke` call site found last, by inline cache slot.
+    inline_caches: Vec<Option<InlineCache>>,
+}
+
+/// A method looked up by an `Invoke` call site, valid for receivers of the same class.
+#[derive(Clone)]
+struct InlineCache {
+    /// The heap id of the class the method was found on.
+    class: usize,
+    method: Value,
+}
+
+/// The names the VM and the natives look up by themselves, interned once when the VM is created
+/// instead of on every lookup.
+pub(crate) struct Symbols {
+    pub(crate) init: Symbol,
+    pub(crate) get: Symbol,
+    pub(crate) set: Symbol,
+    pub(crate) to_string: Symbol,
+    /// The fields of the native classes.
+    pub(crate) items: Symbol,
+    pub(crate) entries: Symbol,
+    pub(crate) index: Symbol,
+    pub(crate) done: Symbol,
+}
+
+impl Symbols {
+    fn new(strings: &mut Interner) -> Symbols {
+        Symbols {
+            init: strings.intern("init"),
+            get: strings.intern("get"),
+            set: strings.intern("set"),
+            to_string: strings.intern("toString"),
+            items: strings.intern("items"),
+            entries: strings.intern("entries"),
+            index: strings.intern("index"),
+            done: strings.intern("done"),
+        }
+    }
 }
 
 pub trait Collectable: Any {
@@ -32,12 +119,10 @@ pub trait Collectable: Any {
 
 impl Collectable for Instance {
     fn collect(&self) -> Vec<usize> {
-        let mut ids = vec![];
+        let mut ids = vec![self.class];
 
-        for (_, value) in &self.fields {
-            if let Value::Instance(id) = value {
-                ids.push(*id);
-            }
+        for value in self.fields.values() {
+            ids.extend(value.collect());
         }
 
         ids
@@ -55,30 +140,47 @@ impl Collectable for Instance {
 impl VM {
     pub fn new(program: Program) -> VM {
         let global_frame = CallFrame {
-            function: Function {
-                instructions: program.instructions,
-                arity: 0,
-                upvalues: HashMap::new(),
-            },
+            closure: Rc::new(Closure::new(Rc::new(Function::new("<script>", program.instructions, program.spans, 0)))),
             ip: 0,
             base_pointer: 0,
         };
 
+        let mut strings = program.strings;
+        let symbols = Symbols::new(&mut strings);
         let mut vm = VM {
             call_stack: vec![global_frame],
             stack: vec![],
             globals: vec![],
+            global_names: program.global_names,
             constants: program.constants,
+            classes: program.classes,
+            native_classes: HashMap::new(),
+            names: program.names,
+            strings,
+            symbols,
             heap: HashMap::new(),
             next_id: 0,
+            open_upvalues: vec![],
+            inline_caches: vec![None; program.inline_cache_count],
         };
 
         vm.globals.resize(program.global_count, None);
 
+        for class in native_functions::native_classes(&mut vm.strings) {
+            let name = class.name.clone();
+            let id = vm.new_collectable(class);
+            vm.native_classes.insert(name, id);
+        }
+
         vm
     }
 
-    pub fn run(&mut self) -> Value {
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        self.execute(0)
+    }
+
+    /// Runs until the frame above `depth` returns, and returns its result.
+    fn execute(&mut self, depth: usize) -> Result<Value, RuntimeError> {
         macro_rules! binary_op {
             ($op:tt, $type:tt) => {
                 let b = self.pop();
@@ -87,7 +189,26 @@ impl VM {
                 if let (Value::Number(a), Value::Number(b)) = (a, b) {
                     self.push(Value::$type(a $op b ));
                 } else {
-                    panic!("Invalid operands for binary operation.");
+                    return Err(self.runtime_error("Invalid operands for binary operation."));
+                }
+            };
+        }
+
+        macro_rules! bitwise_op {
+            ($symbol:literal, $apply:expr) => {
+                let b = self.pop();
+                let a = self.pop();
+                let (a, b) = match (self.integer_operand(&a), self.integer_operand(&b)) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Err(self.runtime_error(
+                        &format!("Operands of '{}' must be integers, got {} and {}.", $symbol, a.to_string(self), b.to_string(self)),
+                    )),
+                };
+                match $apply(a, b) {
+                    Some(result) => self.push(Value::Number(result as f64)),
+                    None => return Err(self.runtime_error(
+                        &format!("Shift amount {} is out of range for '{}'.", b, $symbol),
+                    )),
                 }
             };
         }
@@ -95,7 +216,7 @@ impl VM {
         loop {
             let frame = self.call_stack.last_mut().unwrap();
 
-            let instruction = frame.function.instructions[frame.ip];
+            let instruction = frame.closure.function.instructions[frame.ip];
 
             frame.ip += 1;
 
@@ -109,7 +230,7 @@ impl VM {
                     if let Value::Number(value) = value {
                         self.push(Value::Number(-value));
                     } else {
-                        panic!("Invalid operand for negation.");
+                        return Err(self.runtime_error("Invalid operand for negation."));
                     }
                 }
                 Instruction::Add => {
@@ -119,11 +240,11 @@ impl VM {
                     if let (Value::Number(a), Value::Number(b)) = (a.clone(), b.clone()) {
                         self.push(Value::Number(a + b));
                     } else if let Value::String(a) = a {
-                        self.push(Value::String(a + &b.to_string(self)));
+                        self.push(Value::String(format!("{}{}", a, b.to_string(self)).into()));
                     } else if let Value::String(b) = b {
-                        self.push(Value::String(a.to_string(self) + &b));
+                        self.push(Value::String(format!("{}{}", a.to_string(self), b).into()));
                     } else {
-                        panic!("Invalid operands for addition.");
+                        return Err(self.runtime_error("Invalid operands for addition."));
                     }
                 }
                 Instruction::Subtract => {
@@ -135,13 +256,59 @@ impl VM {
                 Instruction::Divide => {
                     binary_op!(/, Number);
                 }
+                Instruction::Modulo => {
+                    let b = self.pop();
+                    let a = self.pop();
+
+                    // Floored, so the result has the sign of the divisor: `-7 % 3` is 2.
+                    if let (Value::Number(a), Value::Number(b)) = (a, b) {
+                        self.push(Value::Number(a - b * (a / b).floor()));
+                    } else {
+                        return Err(self.runtime_error("Invalid operands for binary operation."));
+                    }
+                }
+                Instruction::Power => {
+                    let b = self.pop();
+                    let a = self.pop();
+
+                    if let (Value::Number(a), Value::Number(b)) = (a, b) {
+                        self.push(Value::Number(a.powf(b)));
+                    } else {
+                        return Err(self.runtime_error("Invalid operands for binary operation."));
+                    }
+                }
+                Instruction::BitAnd => {
+                    bitwise_op!("&", |a: i64, b: i64| Some(a & b));
+                }
+                Instruction::BitOr => {
+                    bitwise_op!("|", |a: i64, b: i64| Some(a | b));
+                }
+                Instruction::BitXor => {
+                    bitwise_op!("^", |a: i64, b: i64| Some(a ^ b));
+                }
+                Instruction::ShiftLeft => {
+                    bitwise_op!("<<", |a: i64, b: i64| u32::try_from(b).ok().and_then(|b| a.checked_shl(b)));
+                }
+                Instruction::ShiftRight => {
+                    bitwise_op!(">>", |a: i64, b: i64| u32::try_from(b).ok().and_then(|b| a.checked_shr(b)));
+                }
+                Instruction::BitNot => {
+                    let value = self.pop();
+
+                    if let Some(value) = self.integer_operand(&value) {
+                        self.push(Value::Number(!value as f64));
+                    } else {
+                        let message = format!("Operand of '~' must be an integer, got {}.", value.to_string(self));
+                        return Err(self.runtime_error(&message));
+                    }
+                }
                 Instruction::Not => {
                     let value = self.pop();
 
                     if let Value::Boolean(value) = value {
                         self.push(Value::Boolean(!value));
                     } else {
-                        panic!("Invalid operand for not operation.");
+                        return Err(self.runtime_error("Invalid operand for not operation."));
                     }
                 }
                 Instruction::Equal => {
@@ -178,26 +345,51 @@ impl VM {
                         frame.ip += offset - 1;
                     }
                 }
+                Instruction::JumpIfFalseOrPop(offset) => {
+                    if self.stack.last().unwrap().is_falsey() {
+                        frame.ip += offset - 1;
+                    } else {
+                        self.stack.pop();
+                    }
+                }
+                Instruction::JumpIfTrueOrPop(offset) => {
+                    if self.stack.last().unwrap().is_truthy() {
+                        frame.ip += offset - 1;
+                    } else {
+                        self.stack.pop();
+                    }
+                }
                 Instruction::JumpBack(offset) => {
                     frame.ip -= offset + 1;
                 }
                 Instruction::Pop => {
                     self.pop();
                 },
+                Instruction::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                },
+                Instruction::Dup => {
+                    self.push(self.peek(1));
+                },
+                Instruction::DupPair => {
+                    self.push(self.peek(2));
+                    self.push(self.peek(2));
+                },
                 Instruction::GetGlobal(index) => {
                     let value = self.globals[index].clone();
 
                     if let Some(value) = value {
                         self.push(value);
                     } else {
-                        panic!("Undefined variable.");
+                        return Err(self.runtime_error(&format!("Undefined variable '{}'.", self.global_names[index])));
                     }
                 },
                 Instruction::SetGlobal(index) => {
                     let value = self.peek(1);
 
                     if self.globals[index].is_none() {
-                        panic!("Undefined variable.");
+                        return Err(self.runtime_error(&format!("Undefined variable '{}'.", self.global_names[index])));
                     } else {
                         self.globals[index] = Some(value);
                     }
@@ -218,167 +410,97 @@ impl VM {
                     self.stack[frame.base_pointer + index] = value;
                 },
                 Instruction::GetProperty(index) => {
-                    let name = self.constants[index].clone();
-                    let instance = self.pop();
+                    let object = self.pop();
 
-                    if let (Value::Instance(instance), Value::String(name)) = (instance.clone(), name) {
+                    if let Value::Instance(instance) = object {
+                        let name = &self.names[index];
                         let instance = self.get_instance(instance).unwrap();
-                        if let Some(value) = instance.fields.get(&name) {
+                        if let Some(value) = instance.fields.get(name) {
                             self.push(value.clone());
                         } else {
-                            let method = self.get_method(instance, name);
-                            self.push(method);
+                            let method = self.get_method(instance, name)?;
+                            self.push(Value::BoundMethod(Box::new(BoundMethod { receiver: object, method })));
                         }
                     } else {
-                        panic!("Cannot get property of non-object.");
+                        return Err(self.runtime_error("Cannot get property of non-object."));
                     }
                 },
                 Instruction::SetProperty(index) => {
-                    let name = self.constants[index].clone();
+                    let name = self.names[index].clone();
                     let value = self.pop();
-                    let instance = self.peek(1);
+                    let instance = self.pop();
 
-                    if let (Value::Instance(instance), Value::String(name)) = (instance, name) {
+                    if let Value::Instance(instance) = instance {
                         let instance = self.get_instance_mut(instance).unwrap();
-                        instance.fields.insert(name, value);
+                        instance.fields.insert(name, value.clone());
+                        self.push(value);
                     } else {
-                        panic!("Cannot set property of non-object.");
+                        return Err(self.runtime_error("Cannot set property of non-object."));
                     }
                 },
-                Instruction::MakeUpvalue(upvalue_index, local_index) => {
-                    let value = self.stack[frame.base_pointer + local_index].clone();
-                    frame.function.upvalues.insert(upvalue_index, self.next_id);
-                    self.new_collectable(value);
-                },
-                Instruction::GetUpvalue(upvalue_index) => {
-                    let closure = frame.function.clone();
-                    let upvalue = closure.upvalues[&upvalue_index].clone();
-                    let value = self.get_collectable::<Value>(upvalue).unwrap();
-                    self.push(value.clone());
+                Instruction::GetUpvalue(index) => {
+                    let id = frame.closure.upvalues[index];
+                    let value = match self.get_collectable::<Upvalue>(id).unwrap() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
                 },
-                Instruction::SetUpvalue(upvalue_index) => {
+                Instruction::SetUpvalue(index) => {
+                    let id = frame.closure.upvalues[index];
                     let value = self.stack.last().unwrap().clone();
-                    let closure = frame.function.clone();
-                    let upvalue = closure.upvalues[&upvalue_index].clone();
-                    self.set_collectable(upvalue, value);
-                },
-                Instruction::MakeClosure => {
-                    let function = self.pop();
-                    let frame_closure = self.call_stack.last().unwrap().function.clone();
-                    if let Value::Function(mut function) = function {
-                        for (index, upvalue) in frame_closure.upvalues.iter() {
-                            function.upvalues.insert(*index,*upvalue);
+                    match self.get_collectable_mut::<Upvalue>(id).unwrap() {
+                        Upvalue::Open(slot) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
                         }
-
-                        self.push(Value::Function(function));
-                    } else {
-                        panic!("Cannot make closure of non-function.");
+                        Upvalue::Closed(closed) => *closed = value,
                     }
                 },
-                Instruction::Call(arg_count) => {
-                    let function = self.peek(arg_count + 1);
+                Instruction::MakeClosure => {
+                    let function = self.pop();
                     if let Value::Function(function) = function {
-                        if function.arity != arg_count {
-                            panic!("Expected {} arguments but got {}.", function.arity, arg_count);
-                        }
-
-                        let base_pointer = self.stack.len() - arg_count;
-                        self.call_stack.push(CallFrame {
-                            function,
-                            base_pointer,
-                            ip: 0,
-                        });
-                    } else if let Value::Native(function) = function {
-                        let mut args = Vec::with_capacity(arg_count);
-                        for _ in 0..arg_count {
-                            args.push(self.pop());
-                        }
-                        self.pop();
-                        let result = (function.function)(args.into_iter().rev().collect(), self);
-                        self.push(result);
-                    } else if let Value::Class(class) = function {
-                        let value = self.new_instance(Instance::new(class.clone()));
-                        let l = self.stack.len();
-                        self.stack[l - arg_count - 1] = value.clone();
-                        let init = class.methods.get("init");
-                        if let Some(Value::Function(init)) = init {
-                            let args = self.stack.split_off(l - arg_count);
-                            self.push(value.clone());
-                            self.stack.extend(args);
-                            self.call_stack.push(CallFrame {
-                                function: init.clone(),
-                                base_pointer: self.stack.len() - arg_count - 1,
-                                ip: 0,
-                            });
-                        } else if let Some(Value::Native(init)) = init {
-                            let mut args = Vec::with_capacity(arg_count + 1);
-                            for _ in 0..arg_count {
-                                args.push(self.pop());
-                            }
-                            args.push(value.clone());
-                            self.pop();
-                            (init.function)(args.into_iter().rev().collect(), self);
-                            self.push(value);
-                        } else if arg_count != 0 {
-                            panic!("Expected 0 arguments, got {}.", arg_count);
-                        }
+                        let closure = self.make_closure(function);
+                        self.push(Value::Closure(Rc::new(closure)));
                     } else {
-                        panic!("Cannot call non-function.");
+                        return Err(self.runtime_error("Cannot make closure of non-function."));
                     }
                 },
+                Instruction::Call(arg_count) => self.call_value(arg_count)?,
                 Instruction::Return => {
                     let return_value = self.pop();
                     let call_frame = self.call_stack.pop().unwrap();
+                    self.close_upvalues(call_frame.base_pointer);
                     self.stack.truncate(call_frame.base_pointer);
-                    if !self.call_stack.is_empty() {
+                    if self.call_stack.len() > depth {
                         let function = self.pop();
-                        if let Value::Function(function) = function {
+                        if let Value::Closure(_) = function {
                             self.push(return_value);
                         } else if let Value::Instance(instance) = function {
                             self.push(Value::Instance(instance));
                         } else {
-                            panic!("Cannot return from non-function.");
+                            return Err(self.runtime_error("Cannot return from non-function."));
                         }
 
                     } else {
-                        return return_value;
-                    }
-                },
-                Instruction::Invoke(arg_count) => {
-                    let name = self.pop();
-                    let instance = self.pop();
-
-                    if let (Value::String(name), Value::Instance(i)) = (name, instance) {
-                        let instance = self.get_instance(i).unwrap();
-                        let method = if let Value::Function(method) = self.get_method(instance, name) {
-                            method
-                        } else {
-                            panic!("Undefined method.");
-                        };
-                        self.push(Value::Function(method.clone()));
-                        self.push(Value::Instance(i));
-                        self.call_stack.push(CallFrame {
-                            function: method,
-                            base_pointer: self.stack.len() - arg_count - 1,
-                            ip: 0,
-                        });
-                    } else {
-                        panic!("Cannot invoke non-method.");
+                        return Ok(return_value);
                     }
                 },
+                Instruction::Invoke(name, arg_count, cache) => self.invoke(name, arg_count, cache)?,
                 Instruction::GetSuper(index) => {
-                    let name = self.constants[index].clone();
                     let superclass = self.pop();
 
-                    if let (Value::String(name), Value::Class(superclass)) = (name, superclass) {
-                        let method = if let Some(Value::Function(method)) = superclass.methods.get(&name) {
+                    if let Value::Class(superclass) = superclass {
+                        let name = &self.names[index];
+                        let superclass = self.get_collectable::<Class>(superclass).unwrap();
+                        let method = if let Some(Value::Closure(method)) = superclass.methods.get(name) {
                             method.clone()
                         } else {
-                            panic!("Undefined method '{}'.", name);
+                            return Err(self.runtime_error(&format!("Undefined method '{}'.", name)));
                         };
-                        self.push(Value::Function(method.clone()));
+                        self.push(Value::Closure(method));
                     } else {
-                        panic!("Cannot get super of non-class.");
+                        return Err(self.runtime_error("Cannot get super of non-class."));
                     }
                 },
                 Instruction::False => {
@@ -390,50 +512,108 @@ impl VM {
                 Instruction::Nil => {
                     self.push(Value::Nil);
                 },
-                Instruction::And => {
-                    let right = self.pop();
-                    let left = self.pop();
-                    if left.is_falsey() {
-                        self.push(left);
-                    } else {
-                        self.push(right);
-                    }
-                },
-                Instruction::Or => {
-                    let right = self.pop();
-                    let left = self.pop();
-
-                    if left.is_truthy() {
-                        self.push(left);
-                    } else {
-                        self.push(right);
-                    }
-                },
                 Instruction::Print => {
                     let value = self.pop();
                     println!("{}", value.to_string(self));
                 },
                 Instruction::Halt => {
-                    return Value::Nil;
+                    return Ok(Value::Nil);
+                },
+                Instruction::BuildString(count) => {
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    let string: String = parts.iter().map(|part| part.to_string(self)).collect();
+                    self.push(Value::String(string.into()));
+                },
+                Instruction::BuildList(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    let list = native_functions::new_list(items, self);
+                    self.push(list);
+                },
+                Instruction::BuildMap(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count * 2);
+                    let mut entries = Vec::with_capacity(count);
+                    for pair in values.chunks(2) {
+                        if let Value::String(key) = &pair[0] {
+                            entries.push((self.strings.intern(key), pair[1].clone()));
+                        } else {
+                            return Err(self.runtime_error("Map keys must be strings."));
+                        }
+                    }
+                    let map = native_functions::new_map(entries, self);
+                    self.push(map);
+                },
+                Instruction::GetIndex => {
+                    let method = self.index_method(&self.peek(2), &self.symbols.get)?;
+                    self.call_method(method, 1)?;
+                },
+                Instruction::SetIndex => {
+                    let method = self.index_method(&self.peek(3), &self.symbols.set)?;
+                    let value = self.peek(1);
+                    let len = self.stack.len();
+                    self.stack.insert(len - 3, value);
+                    self.call_method(method, 2)?;
                 },
                 Instruction::Inherit => {
-                    let subclass = self.pop();
-                    let superclass = self.pop();
+                    let superclass = self.peek(1);
+                    let subclass = self.peek(2);
 
-                    if let (Value::Class(superclass), Value::Class(mut subclass)) = (superclass, subclass) {
-                        for method in superclass.methods {
-                            subclass.methods.insert(method.0, method.1);
+                    if let (Value::Class(superclass), Value::Class(subclass)) = (superclass, subclass) {
+                        let methods = self.get_collectable::<Class>(superclass).unwrap().methods.clone();
+                        let subclass = self.get_collectable_mut::<Class>(subclass).unwrap();
+                        for (name, method) in methods {
+                            subclass.methods.entry(name).or_insert(method);
                         }
-                        self.push(Value::Class(subclass));
                     } else {
-                        panic!("Cannot inherit from non-class.");
+                        return Err(self.runtime_error("Cannot inherit from non-class."));
+                    }
+                },
+                Instruction::Class(index) => {
+                    // Methods of a class declared inside a function close over its variables.
+                    let mut class = self.classes[index].clone();
+                    for method in class.methods.values_mut() {
+                        if let Value::Closure(closure) = method {
+                            if !closure.function.captures.is_empty() {
+                                let function = closure.function.clone();
+                                *method = Value::Closure(Rc::new(self.make_closure(function)));
+                            }
+                        }
                     }
+                    let id = self.new_collectable(class);
+                    self.push(Value::Class(id));
+                },
+                Instruction::GetNativeClass(index) => {
+                    let id = self.native_class(&self.names[index]);
+                    self.push(Value::Class(id));
                 },
             }
         }
 
     }
 
+    /// The value as an integer for the bitwise operators, or `None` if it isn't an integral
+    /// number that fits into 64 bits.
+    fn integer_operand(&self, value: &Value) -> Option<i64> {
+        match value {
+            Value::Number(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    /// Builds an error for `message` carrying the current Horst stack trace.
+    pub(crate) fn runtime_error(&self, message: &str) -> RuntimeError {
+        RuntimeError {
+            message: message.to_string(),
+            trace: self.call_stack
+                .iter()
+                .rev()
+                .map(|frame| TraceFrame {
+                    function: frame.closure.function.name.clone(),
+                    span: frame.span(),
+                })
+                .collect(),
+        }
+    }
+
     fn peek(&self, distance: usize) -> Value {
         self.stack[self.stack.len() - distance].clone()
     }
@@ -446,54 +626,266 @@ impl VM {
         self.stack.push(value);
     }
 
-    fn get_method(&self, instance: &Instance, name: String) -> Value {
-        if let Some(value) = instance.class.methods.get(&name) {
-            return value.clone();
-        } else {
-            panic!("Undefined property {}.", name);
+    /// Finds the `get` or `set` method an index expression on `object` dispatches to.
+    fn index_method(&self, object: &Value, name: &Symbol) -> Result<Value, RuntimeError> {
+        let instance = match object {
+            Value::Instance(id) => self.get_instance(*id).unwrap(),
+            _ => return Err(self.runtime_error("Cannot index non-object.")),
+        };
+
+        let class = self.get_collectable::<Class>(instance.class).unwrap();
+        match class.methods.get(name) {
+            Some(method) => Ok(method.clone()),
+            None => Err(self.runtime_error(&format!("Cannot index instance of '{}' without a '{}' method.", class.name, name))),
         }
     }
 
+    /// Calls the callee below the `arg_count` arguments on top of the stack. Horst functions
+    /// get a new frame, everything else is replaced by its result right away.
+    fn call_value(&mut self, mut arg_count: usize) -> Result<(), RuntimeError> {
+        let mut function = self.peek(arg_count + 1);
+        // How many of the arguments are a receiver the caller didn't pass itself.
+        let mut receivers = 0;
+        if let Value::BoundMethod(bound) = function {
+            let callee = self.stack.len() - arg_count - 1;
+            self.stack[callee] = bound.method.clone();
+            self.stack.insert(callee + 1, bound.receiver);
+            arg_count += 1;
+            receivers = 1;
+            function = bound.method;
+        }
 
-    pub fn mark_and_sweep(&mut self) {
-        // Step 1: Mark
-        let mut marked = vec![];
-        for value in self.stack.clone() {
-            if let Value::Instance(id) = value {
-                marked.push(id);
+        if let Value::Closure(closure) = function {
+            self.check_arity(closure.function.arity, arg_count, receivers)?;
+
+            let base_pointer = self.stack.len() - arg_count;
+            self.call_stack.push(CallFrame {
+                closure,
+                base_pointer,
+                ip: 0,
+            });
+        } else if let Value::Native(function) = function {
+            if let Some(arity) = function.arity {
+                self.check_arity(arity, arg_count, receivers)?;
             }
+
+            let mut args = Vec::with_capacity(arg_count);
+            for _ in 0..arg_count {
+                args.push(self.pop());
+            }
+            self.pop();
+            let result = (function.function)(args.into_iter().rev().collect(), self)?;
+            self.push(result);
+        } else if let Value::Class(class) = function {
+            let value = self.new_instance(Instance::new(class));
+            let l = self.stack.len();
+            self.stack[l - arg_count - 1] = value.clone();
+            let init = self.get_collectable::<Class>(class).unwrap().methods.get(&self.symbols.init).cloned();
+            if let Some(Value::Closure(init)) = init {
+                // The receiver takes up one of init's parameters.
+                let arity = init.function.arity - 1;
+                if arity != arg_count {
+                    return Err(self.runtime_error(&format!("Expected {} arguments but got {}.", arity, arg_count)));
+                }
+
+                let args = self.stack.split_off(l - arg_count);
+                self.push(value.clone());
+                self.stack.extend(args);
+                self.call_stack.push(CallFrame {
+                    closure: init,
+                    base_pointer: self.stack.len() - arg_count - 1,
+                    ip: 0,
+                });
+            } else if let Some(Value::Native(init)) = init {
+                if let Some(arity) = init.arity {
+                    self.check_arity(arity, arg_count + 1, 1)?;
+                }
+
+                let mut args = Vec::with_capacity(arg_count + 1);
+                for _ in 0..arg_count {
+                    args.push(self.pop());
+                }
+                args.push(value.clone());
+                self.pop();
+                (init.function)(args.into_iter().rev().collect(), self)?;
+                self.push(value);
+            } else if arg_count != 0 {
+                return Err(self.runtime_error(&format!("Expected 0 arguments, got {}.", arg_count)));
+            }
+        } else {
+            return Err(self.runtime_error("Cannot call non-function."));
         }
-        let mut swap = marked.clone();
-        let mut current = vec![];
-        loop {
-            for a in &swap {
-                if let Some(instance) = self.heap.get(a) {
-                    match self.get_instance(*a) {
-                        Some(instance) => {
-                            for (_, value) in &instance.fields {
-                                if let Value::Instance(id) = value {
-                                    if !marked.contains(id) {
-                                        current.push(*id);
-                                    }
-                                }
-                            }
-                        },
-                        None => (),
-                    }
+
+        Ok(())
+    }
+
+    /// Calls the method named by the constant at `name` on the receiver below the `arg_count`
+    /// arguments on top of the stack, as `GetProperty` and `Call` would but without binding the
+    /// method. The method is looked up once per class and call site and then found in the
+    /// site's inline cache until a receiver of another class shows up.
+    fn invoke(&mut self, name: usize, arg_count: usize, cache: usize) -> Result<(), RuntimeError> {
+        let receiver = self.stack.len() - arg_count - 1;
+        let instance = match &self.stack[receiver] {
+            Value::Instance(id) => self.get_instance(*id).unwrap(),
+            _ => return Err(self.runtime_error("Cannot get property of non-object.")),
+        };
+        let name = &self.names[name];
+
+        // Fields shadow methods, and functions stored in them don't take the receiver.
+        if let Some(field) = instance.fields.get(name) {
+            self.stack[receiver] = field.clone();
+            return self.call_value(arg_count);
+        }
+
+        let class = instance.class;
+        let method = match &self.inline_caches[cache] {
+            Some(entry) if entry.class == class => entry.method.clone(),
+            _ => {
+                let method = self.get_method(instance, name)?;
+                self.inline_caches[cache] = Some(InlineCache { class, method: method.clone() });
+                method
+            }
+        };
+        self.call_method(method, arg_count)
+    }
+
+    /// Calls `method` with the receiver and `arg_count` arguments on top of the stack. The
+    /// result replaces them, once a Horst method returns.
+    fn call_method(&mut self, method: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        let receiver = self.stack.len() - arg_count - 1;
+
+        match method {
+            Value::Closure(closure) => {
+                self.check_arity(closure.function.arity, arg_count + 1, 1)?;
+
+                self.stack.insert(receiver, Value::Closure(closure.clone()));
+                self.call_stack.push(CallFrame {
+                    closure,
+                    base_pointer: receiver + 1,
+                    ip: 0,
+                });
+            }
+            Value::Native(native) => {
+                if let Some(arity) = native.arity {
+                    self.check_arity(arity, arg_count + 1, 1)?;
                 }
+
+                let args = self.stack.split_off(receiver);
+                let result = (native.function)(args, self)?;
+                self.push(result);
             }
-            if !current.is_empty() {
-                marked.append(&mut current);
-                swap = current.clone();
-                current.clear();
-            } else {
-                break;
+            _ => return Err(self.runtime_error("Cannot call non-function.")),
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a callee taking `arity` arguments gets `arg_count` of them. The first
+    /// `receivers` of both are a receiver the caller didn't pass itself, which the error leaves
+    /// out so that it counts what the script wrote.
+    fn check_arity(&self, arity: usize, arg_count: usize, receivers: usize) -> Result<(), RuntimeError> {
+        if arity == arg_count {
+            return Ok(());
+        }
+        Err(self.runtime_error(&format!("Expected {} arguments but got {}.", arity - receivers, arg_count - receivers)))
+    }
+
+    /// Calls `method` on `receiver` without arguments and runs it to completion, for natives
+    /// that need the result of a method that may be written in Horst. The stack and the call
+    /// stack are left as they were, also when the method fails.
+    pub(crate) fn call_method_now(&mut self, receiver: Value, method: Value) -> Result<Value, RuntimeError> {
+        let depth = self.call_stack.len();
+        let start = self.stack.len();
+        self.push(receiver);
+
+        let result = match self.call_method(method, 0) {
+            Ok(()) if self.call_stack.len() > depth => self.execute(depth),
+            Ok(()) => Ok(self.pop()),
+            Err(error) => Err(error),
+        };
+
+        if result.is_err() {
+            self.close_upvalues(start);
+            self.call_stack.truncate(depth);
+        }
+        self.stack.truncate(start);
+        result
+    }
+
+    /// Creates a closure of `function` in the current frame, capturing the frame's locals and
+    /// upvalues the function refers to.
+    fn make_closure(&mut self, function: Rc<Function>) -> Closure {
+        let frame = self.call_stack.last().unwrap();
+        let base_pointer = frame.base_pointer;
+        let enclosing = frame.closure.clone();
+        let upvalues = function.captures.iter().map(|capture| match *capture {
+            Capture::Local(slot) => self.capture_upvalue(base_pointer + slot),
+            Capture::Upvalue(index) => enclosing.upvalues[index],
+        }).collect();
+
+        Closure { function, upvalues }
+    }
+
+    /// Returns the open upvalue for the stack slot at `slot`, creating it if no closure has
+    /// captured the slot yet, so that all closures over a variable share one upvalue.
+    fn capture_upvalue(&mut self, slot: usize) -> usize {
+        let existing = self.open_upvalues.iter().copied().find(|&id| {
+            self.get_collectable::<Upvalue>(id) == Some(&Upvalue::Open(slot))
+        });
+
+        existing.unwrap_or_else(|| {
+            let id = self.new_collectable(Upvalue::Open(slot));
+            self.open_upvalues.push(id);
+            id
+        })
+    }
+
+    /// Closes the open upvalues of the stack slots from `slot` up, moving their values into the
+    /// upvalues before the slots are popped.
+    fn close_upvalues(&mut self, slot: usize) {
+        let open_upvalues = std::mem::take(&mut self.open_upvalues);
+        for id in open_upvalues {
+            match self.get_collectable::<Upvalue>(id) {
+                Some(&Upvalue::Open(open)) if open >= slot => {
+                    let value = self.stack[open].clone();
+                    self.set_collectable(id, Upvalue::Closed(value));
+                }
+                _ => self.open_upvalues.push(id),
             }
         }
+    }
+
+    fn get_method(&self, instance: &Instance, name: &Symbol) -> Result<Value, RuntimeError> {
+        if let Some(value) = self.get_collectable::<Class>(instance.class).unwrap().methods.get(name) {
+            Ok(value.clone())
+        } else {
+            Err(self.runtime_error(&format!("Undefined property '{}'.", name)))
+        }
+    }
 
 
-        // Step 2: Sweep
+    pub fn mark_and_sweep(&mut self) {
+        // Step 1: Mark everything reachable from the stack, the globals, the closures being
+        // executed and the native classes.
+        let mut pending: Vec<usize> = self.stack.iter()
+            .chain(self.globals.iter().flatten())
+            .flat_map(Value::collect)
+            .chain(self.call_stack.iter().flat_map(|frame| frame.closure.upvalues.iter().copied()))
+            .chain(self.open_upvalues.iter().copied())
+            .chain(self.native_classes.values().copied())
+            .collect();
+        let mut marked = HashSet::new();
+        while let Some(id) = pending.pop() {
+            if marked.insert(id) {
+                if let Some(collectable) = self.heap.get(&id) {
+                    pending.extend(collectable.collect());
+                }
+            }
+        }
+
+        // Step 2: Sweep, then forget the strings only the swept objects used as names.
         self.heap.retain(|id, _| marked.contains(id));
+        self.strings.prune();
     }
 
     pub fn new_instance(&mut self, instance: Instance) -> Value {
@@ -517,6 +909,16 @@ impl VM {
         }
     }
 
+    /// Returns the symbol for `string` from the VM's string table.
+    pub(crate) fn intern(&mut self, string: &str) -> Symbol {
+        self.strings.intern(string)
+    }
+
+    /// Returns the heap id of the native class called `name`.
+    pub(crate) fn native_class(&self, name: &str) -> usize {
+        self.native_classes[name]
+    }
+
     pub fn get_collectable<T: Collectable>(&self, id: usize) -> Option<&T> {
         match self.heap.get(&id) {
             Some(collectable) => collectable.as_any().downcast_ref::<T>(),
@@ -541,4 +943,1061 @@ impl VM {
         self.heap.insert(id, Box::new(collectable));
         id
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use super::*;
+
+    fn run(source: &str) -> Result<Value, RuntimeError> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let program = Compiler::new().compile(&statements).unwrap();
+        VM::new(program).run()
+    }
+
+    /// Runs `source` and checks that it leaves nothing behind on the stack.
+    fn run_balanced(source: &str) -> Result<Value, RuntimeError> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        let result = vm.run();
+        assert!(vm.stack.is_empty(), "{:?}", vm.stack);
+        result
+    }
+
+    #[test]
+    fn test_returns_value() {
+        assert_eq!(run("return 1 + 2;"), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_type_error() {
+        let error = run("let x = 1;\nreturn x - \"a\";").unwrap_err();
+        assert_eq!(error.message, "Invalid operands for binary operation.");
+        assert_eq!(error.span().map(|span| span.line), Some(2));
+    }
+
+    #[test]
+    fn test_undefined_variable_trace() {
+        let error = run("fn inner() {\n  return missing;\n}\nfn outer() {\n  return inner();\n}\nouter();").unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'missing'.");
+
+        let trace: Vec<(&str, usize)> = error.trace.iter().map(|frame| (frame.function.as_str(), frame.span.line)).collect();
+        assert_eq!(trace, vec![("inner", 2), ("outer", 5), ("<script>", 7)]);
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let error = run("fn f(a, b) { return a; }\nf(1);").unwrap_err();
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+    }
+
+    #[test]
+    fn test_init_arity_mismatch() {
+        let source = "class P { init(a, b) { this.a = a; this.b = b; } }\n";
+        let error = run(&format!("{}P(1);", source)).unwrap_err();
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+        assert_eq!(error.span().map(|span| span.line), Some(2));
+
+        let error = run(&format!("{}P(1, 2, 3);", source)).unwrap_err();
+        assert_eq!(error.message, "Expected 2 arguments but got 3.");
+
+        assert_eq!(run(&format!("{}return P(1, 2).b;", source)), Ok(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_call_non_function() {
+        let error = run("let x = 1; x();").unwrap_err();
+        assert_eq!(error.message, "Cannot call non-function.");
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let source = "
+            let calls = 0;
+            fn touch(value) { calls = calls + 1; return value; }
+            let a = false and touch(true);
+            let b = true or touch(false);
+            let x = nil;
+            let c = x != nil and x.field;
+            return calls;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(0.0)));
+
+        let source = "
+            let calls = 0;
+            fn touch(value) { calls = calls + 1; return value; }
+            let a = true and touch(1);
+            let b = nil or touch(2);
+            return calls;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_logical_operators_return_deciding_operand() {
+        assert_eq!(run("return false and 1;"), Ok(Value::Boolean(false)));
+        assert_eq!(run("return nil or \"x\";"), Ok(Value::String("x".into())));
+        assert_eq!(run("return 1 and 2;"), Ok(Value::Number(2.0)));
+        assert_eq!(run("return 1 or 2;"), Ok(Value::Number(1.0)));
+        assert_eq!(run("let i = 0; while (i < 3 and true) { i = i + 1; } return i;"), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let source = "
+            let i = 0;
+            let sum = 0;
+            while (true) {
+                let a = 1;
+                i = i + 1;
+                if (i > 5) { let b = 2; break; }
+                if (i == 2) { let c = 3; continue; }
+                sum = sum + i;
+            }
+            return sum;
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        assert_eq!(vm.run(), Ok(Value::Number(13.0)));
+        assert!(vm.stack.is_empty(), "{:?}", vm.stack);
+    }
+
+    #[test]
+    fn test_labeled_break_and_continue() {
+        let source = "
+            let count = 0;
+            let i = 0;
+            let j = 0;
+            outer: while (i < 3) {
+                i = i + 1;
+                j = 0;
+                while (true) {
+                    let x = nil;
+                    j = j + 1;
+                    if (j == 2) { continue outer; }
+                    count = count + 1;
+                }
+            }
+            return count;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(3.0)));
+
+        let source = "
+            let count = 0;
+            outer: while (true) {
+                while (true) {
+                    count = count + 1;
+                    if (count == 4) { break outer; }
+                }
+            }
+            return count;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let source = "
+            let sum = 0;
+            for (let i = 0; i < 10; i = i + 1) {
+                if (i == 3) { continue; }
+                if (i == 6) { break; }
+                sum = sum + i;
+            }
+            return sum;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(12.0)));
+    }
+
+    #[test]
+    fn test_for_in_list_and_map() {
+        let source = "
+            let list = List(1, 2, 3);
+            let sum = 0;
+            for (item in list) {
+                if (item == 1) { list.add(10); }
+                sum = sum + item;
+            }
+            return sum;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(16.0)));
+
+        let source = "
+            let map = Map();
+            map.set(\"b\", 2);
+            map.set(\"a\", 1);
+            let keys = \"\";
+            for (key in map) { keys = keys + key; }
+            return keys;
+        ";
+        assert_eq!(run(source), Ok(Value::String("ab".into())));
+
+        assert_eq!(run("let n = 0; for (item in List()) { n = n + 1; } return n;"), Ok(Value::Number(0.0)));
+    }
+
+    #[test]
+    fn test_for_in_sees_items_added_before_the_last_one() {
+        let source = "
+            let l = [1, 2];
+            let s = 0;
+            for (x in l) { if (x == 1) { l.add(5); } s += x; }
+            return s;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(8.0)));
+
+        let source = "
+            let l = [1];
+            let s = 0;
+            for (x in l) { if (x == 1) { l.add(5); } s += x; }
+            return s;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_for_in_user_iterator() {
+        let source = "
+            class Countdown {
+                init(from) { this.from = from; }
+                iterator() { return CountdownIterator(this.from); }
+            }
+            class CountdownIterator {
+                init(current) { this.current = current; this.done = current == 0; }
+                next() {
+                    this.current = this.current - 1;
+                    this.done = this.current == 0;
+                    return this.current + 1;
+                }
+            }
+            let visited = \"\";
+            for (n in Countdown(5)) {
+                if (n == 2) { break; }
+                visited = visited + \"x\";
+            }
+            return visited;
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        assert_eq!(vm.run(), Ok(Value::String("xxx".into())));
+        assert!(vm.stack.is_empty(), "{:?}", vm.stack);
+    }
+
+    #[test]
+    fn test_collection_literals() {
+        assert_eq!(run("let list = [1, 2, 3]; return list[1];"), Ok(Value::Number(2.0)));
+        assert_eq!(run("let map = {\"a\": 1, \"b\": [2]}; return map[\"b\"][0];"), Ok(Value::Number(2.0)));
+        assert_eq!(run("return [].toString();"), Ok(Value::String("List([])".into())));
+        assert_eq!(run("let map = {}; return map[\"missing\"];"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_index_assignment() {
+        let source = "
+            let list = [1, 2];
+            let map = {};
+            let result = list[0] = 5;
+            map[\"a\"] = list[0] + list[1];
+            return result + map[\"a\"];
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        assert_eq!(vm.run(), Ok(Value::Number(12.0)));
+        assert!(vm.stack.is_empty(), "{:?}", vm.stack);
+    }
+
+    #[test]
+    fn test_arithmetic_and_bitwise_operators() {
+        assert_eq!(run("return 7 % 3 + 2 ** 10;"), Ok(Value::Number(1025.0)));
+        assert_eq!(run("return -7 % 3;"), Ok(Value::Number(2.0)));
+        assert_eq!(run("return 7 % -3;"), Ok(Value::Number(-2.0)));
+        assert_eq!(run("return -7 % -3;"), Ok(Value::Number(-1.0)));
+        assert_eq!(run("return 7.5 % 2;"), Ok(Value::Number(1.5)));
+        assert_eq!(run("return (12 & 10) + (12 | 10) + (12 ^ 10);"), Ok(Value::Number(28.0)));
+        assert_eq!(run("return ~5 + (1 << 4) + (-16 >> 2);"), Ok(Value::Number(6.0)));
+        assert_eq!(run("let n = 6; return n % 2 == 0 and n & 1 == 0;"), Ok(Value::Boolean(true)));
+    }
+
+    #[test]
+    fn test_bitwise_operators_require_integers() {
+        let error = run("return 1.5 & 1;").unwrap_err();
+        assert_eq!(error.message, "Operands of '&' must be integers, got 1.5 and 1.");
+        let error = run("return ~\"a\";").unwrap_err();
+        assert_eq!(error.message, "Operand of '~' must be an integer, got a.");
+        let error = run("return 1 << 64;").unwrap_err();
+        assert_eq!(error.message, "Shift amount 64 is out of range for '<<'.");
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let source = "
+            class User { init(name) { this.name = name; } }
+            let user = User(\"Ada\");
+            let count = 3;
+            let items = {\"total\": count * 2};
+            return \"Hello ${user.name}, you have ${count} items (${items[\"total\"]} with ${\"${nil} ${true}\"})\";
+        ";
+        assert_eq!(run(source), Ok(Value::String("Hello Ada, you have 3 items (6 with nil true)".into())));
+    }
+
+    #[test]
+    fn test_doc_comments_are_available_at_runtime() {
+        let source = "
+            /// A point in the plane.
+            class Point {
+                /// Returns the origin.
+                origin() { return nil; }
+            }
+            /// Does nothing.
+            fn noop() {}
+            fn undocumented() {}
+            return doc(Point) + \" \" + doc(Point().origin) + \" \" + doc(noop) + \" \" + doc(undocumented);
+        ";
+        assert_eq!(run(source), Ok(Value::String("A point in the plane. Returns the origin. Does nothing. nil".into())));
+    }
+
+    #[test]
+    fn test_function_locals_are_visible_in_nested_blocks() {
+        let source = "
+            fn f(n) {
+                let total = 0;
+                let i = 0;
+                while (i < n) {
+                    if (i % 2 == 0) {
+                        {
+                            if (true) {
+                                total += i;
+                            }
+                        }
+                    } else {
+                        total -= 1;
+                    }
+                    i += 1;
+                }
+                return total;
+            }
+            return f(6);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_nested_block_locals_get_distinct_slots() {
+        let source = "
+            fn f(a, b) {
+                let c = 3;
+                {
+                    let d = 4;
+                    {
+                        let e = 5;
+                        {
+                            let g = a * 10000 + b * 1000 + c * 100 + d * 10 + e;
+                            return g;
+                        }
+                    }
+                }
+            }
+            return f(1, 2);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(12345.0)));
+    }
+
+    #[test]
+    fn test_sibling_blocks_reuse_slots() {
+        let source = "
+            fn f() {
+                let outer = 1;
+                { let a = 10; outer += a; }
+                { let b = 20; { let c = 300; outer += b + c; } }
+                let after = 4000;
+                { let d = 50000; outer += d; }
+                return outer + after;
+            }
+            let result = 0;
+            { let x = 1; { let y = 2; result = x + y; } }
+            { let z = 3; result += z; }
+            return f() + result;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(54337.0)));
+    }
+
+    #[test]
+    fn test_shadowing_restores_outer_variable() {
+        let source = "
+            let x = \"global\";
+            fn f(x) {
+                let seen = x;
+                {
+                    let x = \"block\";
+                    seen += \" \" + x;
+                    {
+                        let x = x + \" inner\";
+                        seen += \" \" + x;
+                        x = \"changed\";
+                    }
+                    seen += \" \" + x;
+                }
+                return seen + \" \" + x;
+            }
+            let result = f(\"param\");
+            {
+                let x = \"top\";
+                result += \" \" + x;
+            }
+            return result + \" \" + x;
+        ";
+        assert_eq!(
+            run_balanced(source),
+            Ok(Value::String("param block block inner block param top global".into())),
+        );
+    }
+
+    #[test]
+    fn test_loops_with_block_locals_keep_the_stack_balanced() {
+        let source = "
+            fn f() {
+                let sum = 0;
+                for (let i = 0; i < 4; i += 1) {
+                    let doubled = i * 2;
+                    for (item in [1, 2, 3]) {
+                        let scaled = item * doubled;
+                        {
+                            let skip = scaled > 8;
+                            if (skip) { break; }
+                            if (item == 2) { continue; }
+                            sum += scaled;
+                        }
+                    }
+                }
+                return sum;
+            }
+            let total = 0;
+            for (n in [f(), 100]) {
+                let copy = n;
+                total += copy;
+            }
+            return total;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(118.0)));
+    }
+
+    #[test]
+    fn test_recursion_with_block_locals() {
+        let source = "
+            fn fib(n) {
+                if (n < 2) {
+                    return n;
+                }
+                {
+                    let a = fib(n - 1);
+                    {
+                        let b = fib(n - 2);
+                        return a + b;
+                    }
+                }
+            }
+            return fib(15);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(610.0)));
+    }
+
+    #[test]
+    fn test_nested_declarations_are_locals() {
+        let source = "
+            let f = 1;
+            fn g() { fn f() { return 2; } return f(); }
+            g();
+            return f;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(1.0)));
+
+        let source = "
+            let Point = 1;
+            fn make() {
+                class Point { x() { return 3; } }
+                return Point().x();
+            }
+            {
+                class Point { x() { return 4; } }
+                Point = Point().x() + make();
+            }
+            return Point;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_nested_functions_recurse() {
+        let source = "
+            fn outer() {
+                fn fact(n) { if (n < 2) { return 1; } return n * fact(n - 1); }
+                return fact(5);
+            }
+            return outer();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(120.0)));
+    }
+
+    #[test]
+    fn test_nested_functions_capture_their_own_closure() {
+        let source = "
+            fn make(n) {
+                fn f(k) { if (k == 0) { return n; } return f(k - 1); }
+                return f;
+            }
+            let a = make(1);
+            let b = make(2);
+            return a(3);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_methods_of_local_classes_capture_variables() {
+        let source = "
+            fn make(step) {
+                let total = 0;
+                class Counter {
+                    add() { total += step; return this; }
+                    copy() { return Counter(); }
+                    total() { return total; }
+                }
+                return Counter;
+            }
+            let Counter = make(2);
+            let Other = make(5);
+            Other().add();
+            return Counter().add().copy().add().total();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(4.0)));
+    }
+
+    #[test]
+    fn test_super_of_local_superclasses() {
+        let source = "
+            fn make() {
+                class Base { name() { return \"base\"; } }
+                class Derived < Base {
+                    name() { return fn() { return \"derived \" + super.name(); }; }
+                }
+                return Derived;
+            }
+            let Derived = make();
+            return Derived().name()();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("derived base".into())));
+
+        let source = "
+            let result = nil;
+            {
+                class Base { name() { return \"base\"; } }
+                class Derived < Base { name() { return \"derived \" + super.name(); } }
+                result = Derived().name();
+            }
+            return result;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("derived base".into())));
+    }
+
+    #[test]
+    fn test_closures_share_captured_variables() {
+        let source = "
+            fn makeCounter() {
+                let count = 0;
+                let increment = fn() { count += 1; return count; };
+                let get = fn() { return count; };
+                increment();
+                increment();
+                let seen = count;
+                count += 10;
+                return [increment, get, seen];
+            }
+            let first = makeCounter();
+            let second = makeCounter();
+            first[0]();
+            second[0]();
+            second[0]();
+            return \"${first[2]} ${first[1]()} ${second[1]()}\";
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("2 13 14".into())));
+    }
+
+    #[test]
+    fn test_closures_capture_each_iteration_separately() {
+        let source = "
+            fn register() {
+                let callbacks = [];
+                let total = 0;
+                for (let i = 1; i <= 3; i += 1) {
+                    let value = i * 10;
+                    callbacks.add(fn() { total += value; return value; });
+                }
+                for (callback in callbacks) {
+                    callback();
+                }
+                return total;
+            }
+            return register();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(60.0)));
+    }
+
+    #[test]
+    fn test_closures_capture_through_enclosing_functions() {
+        let source = "
+            fn outer() {
+                let x = 1;
+                let middle = fn() {
+                    let inner = fn() { x *= 3; return x; };
+                    return inner;
+                };
+                let inner = middle();
+                inner();
+                x += 1;
+                return inner();
+            }
+            return outer();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(12.0)));
+    }
+
+    #[test]
+    fn test_break_closes_upvalues_captured_later_in_the_body() {
+        let source = "
+            fn f() {
+                let callbacks = [];
+                let done = false;
+                outer: while (true) {
+                    let value = \"captured\";
+                    while (true) {
+                        if (done) { break outer; }
+                        callbacks.add(fn() { return value; });
+                        done = true;
+                    }
+                }
+                let reused = \"other\";
+                return callbacks[0]();
+            }
+            return f();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("captured".into())));
+    }
+
+    #[test]
+    fn test_this_inside_closures_nested_in_methods() {
+        let source = "
+            class Base {
+                describe() { return \"count \" + this.count; }
+            }
+            class Counter < Base {
+                init() { this.count = 0; }
+                incrementer() {
+                    let step = 2;
+                    return fn() {
+                        let add = fn(extra) { this.count += step + extra; };
+                        add(1);
+                        return super.describe();
+                    };
+                }
+            }
+            let counter = Counter();
+            let increment = counter.incrementer();
+            increment();
+            return increment() + \" \" + counter.count;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("count 6 6".into())));
+    }
+
+    #[test]
+    fn test_bound_methods_remember_their_receiver() {
+        let source = "
+            class Greeter {
+                init(name) { this.name = name; }
+                greet(greeting) { return greeting + \" \" + this.name; }
+            }
+            fn apply(callback, argument) { return callback(argument); }
+            let greet = Greeter(\"Ada\").greet;
+            return greet(\"Hello\") + \", \" + apply(Greeter(\"Bob\").greet, \"hi\");
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("Hello Ada, hi Bob".into())));
+    }
+
+    #[test]
+    fn test_bound_native_methods() {
+        let source = "
+            let list = [1];
+            let add = list.add;
+            add(2);
+            add(3);
+            let toString = list.toString;
+            return toString();
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("List([1, 2, 3])".into())));
+    }
+
+    #[test]
+    fn test_functions_stored_in_fields_are_not_bound() {
+        let source = "
+            class Box {}
+            let box = Box();
+            box.double = fn(x) { return x * 2; };
+            return box.double(21);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::Number(42.0)));
+    }
+
+    #[test]
+    fn test_invoke_sites_see_receivers_of_different_classes() {
+        let source = "
+            class Base {
+                name() { return \"base\"; }
+                describe() { return this.name(); }
+            }
+            class Sub < Base {
+                name() { return \"sub\"; }
+            }
+            class Other {
+                name() { return \"other\"; }
+            }
+            let shadowed = Other();
+            shadowed.name = fn() { return \"field\"; };
+            let result = \"\";
+            for (object in [Base(), Sub(), Sub(), Other(), shadowed, Base()]) {
+                result += object.name() + \" \";
+            }
+            return result + Sub().describe() + \" \" + Base().describe();
+        ";
+        assert_eq!(
+            run_balanced(source),
+            Ok(Value::String("base sub sub other field base sub base".into())),
+        );
+    }
+
+    #[test]
+    fn test_invoke_errors() {
+        assert_eq!(run("let a = 1; a.m();").unwrap_err().message, "Cannot get property of non-object.");
+        assert_eq!(run("class A {} A().m();").unwrap_err().message, "Undefined property 'm'.");
+        assert_eq!(
+            run("class A { m(x) { return x; } } A().m();").unwrap_err().message,
+            "Expected 1 arguments but got 0.",
+        );
+    }
+
+    #[test]
+    fn test_instances_share_their_class_on_the_heap() {
+        let source = "
+            class Base { name() { return \"base\"; } }
+            class Sub < Base {}
+            let first = Sub();
+            let second = Sub();
+            return \"\" + Sub + \" \" + first.name();
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        assert_eq!(vm.run(), Ok(Value::String("class Sub base".into())));
+
+        vm.mark_and_sweep();
+        let classes = vm.heap.values().filter(|collectable| collectable.as_any().is::<Class>()).count();
+        let instances: Vec<&Instance> = vm.heap.values().filter_map(|collectable| collectable.as_any().downcast_ref()).collect();
+        // Map, List and Iterator are allocated up front.
+        assert_eq!(classes, 5);
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].class, instances[1].class);
+        assert!(vm.get_collectable::<Class>(instances[0].class).is_some());
+    }
+
+    #[test]
+    fn test_mark_and_sweep_keeps_captured_variables() {
+        let source = "
+            fn makeCounter() {
+                let count = 0;
+                return fn() { count += 1; return count; };
+            }
+            let counter = makeCounter();
+            counter();
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        vm.run().unwrap();
+
+        vm.mark_and_sweep();
+        let upvalues: Vec<&Upvalue> = vm.heap.values().filter_map(|collectable| collectable.as_any().downcast_ref()).collect();
+        assert_eq!(upvalues, vec![&Upvalue::Closed(Value::Number(1.0))]);
+    }
+
+    #[test]
+    fn test_mark_and_sweep_keeps_list_items() {
+        let source = "
+            fn make() {
+                class Point {}
+                let count = 0;
+                return [Point, fn() { count += 1; return count; }];
+            }
+            let items = make();
+            items[1]();
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        vm.run().unwrap();
+
+        vm.mark_and_sweep();
+        let classes: Vec<&str> = vm.heap.values()
+            .filter_map(|collectable| collectable.as_any().downcast_ref::<Class>())
+            .map(|class| class.name.as_str())
+            .collect();
+        assert!(classes.contains(&"Point"), "{:?}", classes);
+        let upvalues: Vec<&Upvalue> = vm.heap.values().filter_map(|collectable| collectable.as_any().downcast_ref()).collect();
+        assert_eq!(upvalues, vec![&Upvalue::Closed(Value::Number(1.0))]);
+    }
+
+    #[test]
+    fn test_mark_and_sweep_prunes_runtime_strings() {
+        let source = "
+            let map = {};
+            for (let i = 0; i < 3; i += 1) { map[\"key\" + i] = i; }
+            let kept = {\"lit\" + \"eral\": 1};
+            map = nil;
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        vm.run().unwrap();
+        assert!(format!("{:?}", vm.strings).contains("\"key0\""));
+
+        vm.mark_and_sweep();
+        let strings = format!("{:?}", vm.strings);
+        assert!(!strings.contains("\"key0\"") && !strings.contains("\"key2\""), "{}", strings);
+        assert!(strings.contains("\"literal\""), "{}", strings);
+    }
+
+    #[test]
+    fn test_closures_share_their_function() {
+        let source = "
+            fn make(x) { return fn() { return x; }; }
+            let first = make(1);
+            let second = make(2);
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        vm.run().unwrap();
+
+        let global = |name: &str| {
+            let index = vm.global_names.iter().position(|global| global == name).unwrap();
+            match &vm.globals[index] {
+                Some(Value::Closure(closure)) => closure.clone(),
+                other => panic!("Expected closure, got {:?}.", other),
+            }
+        };
+        let (first, second) = (global("first"), global("second"));
+        assert!(Rc::ptr_eq(&first.function, &second.function));
+        assert_ne!(first.upvalues, second.upvalues);
+    }
+
+    #[test]
+    fn test_runtime_strings_find_properties_by_name() {
+        let source = "
+            let map = {\"name\": 1};
+            let key = \"na\" + \"me\";
+            map[key] = map[key] + 1;
+            map[\"name\"] += 1;
+            return \"${map[\"name\"]} ${key == \"name\"}\";
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("3 true".into())));
+    }
+
+    #[test]
+    fn test_collections_print_classes_by_name() {
+        let source = "
+            class Foo {}
+            return [Foo, 1].toString() + \" \" + {\"foo\": Foo}.toString();
+        ";
+        assert_eq!(run(source), Ok(Value::String("List([class Foo, 1]) {foo: class Foo}".into())));
+    }
+
+    #[test]
+    fn test_list_to_string_calls_horst_methods_inside_functions() {
+        let source = "
+            class Point {
+                init(x) { this.x = x; }
+                toString() { return \"P\" + this.x; }
+            }
+            fn show(a, b) {
+                let list = [Point(a), Point(b)];
+                return list.toString() + \" \" + a + b;
+            }
+            return show(1, 2);
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("List([P1, P2]) 12".into())));
+
+        let source = "class Bad {\n  toString() {\n    return missing;\n  }\n}\nfn show() {\n  return [Bad()].toString();\n}\nshow();";
+        let error = run(source).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'missing'.");
+        let trace: Vec<(&str, usize)> = error.trace.iter().map(|frame| (frame.function.as_str(), frame.span.line)).collect();
+        assert_eq!(trace, vec![("toString", 3), ("show", 7), ("<script>", 9)]);
+    }
+
+    #[test]
+    fn test_map_keys_dont_shadow_methods() {
+        let source = "
+            let map = {\"set\": 1, \"iterator\": 2};
+            map.set(\"get\", 3);
+            let keys = \"\";
+            for (key in map) { keys += key + \"=\" + map.get(key) + \" \"; }
+            return keys;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("get=3 iterator=2 set=1 ".into())));
+        assert_eq!(run("return Map(1);").unwrap_err().message, "Expected 0 arguments but got 1.");
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let source = "
+            let total = 10;
+            fn counter() {
+                let count = 0;
+                fn increment() {
+                    count += 1;
+                    return count;
+                }
+                return increment;
+            }
+            let next = counter();
+            next();
+            total -= next();
+            {
+                let local = 3;
+                local *= total;
+                local /= 2;
+                local %= 5;
+                return local;
+            }
+        ";
+        assert_eq!(run(source), Ok(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_compound_property_assignment() {
+        let source = "
+            class Counter { init() { this.count = 1; } }
+            let counter = Counter();
+            let evaluations = 0;
+            fn get() {
+                evaluations += 1;
+                return counter;
+            }
+            get().count += 5;
+            get().count *= 2;
+            return counter.count + evaluations;
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(tokens).parse().unwrap();
+        let mut vm = VM::new(Compiler::new().compile(&statements).unwrap());
+        assert_eq!(vm.run(), Ok(Value::Number(14.0)));
+        assert!(vm.stack.is_empty(), "{:?}", vm.stack);
+    }
+
+    #[test]
+    fn test_property_assignments_evaluate_to_the_value() {
+        let source = "
+            class Point {}
+            let p = Point();
+            p.n = 1;
+            let r = p.n += 1;
+            let s = p.m = 5;
+            return \"${r} ${s} ${p.n}\";
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("2 5 2".into())));
+    }
+
+    #[test]
+    fn test_compound_index_assignment() {
+        let source = "
+            let list = [1, 2];
+            let map = {\"a\": \"x\"};
+            let evaluations = 0;
+            fn index() {
+                evaluations += 1;
+                return 1;
+            }
+            list[index()] += 40;
+            map[\"a\"] += \"y\";
+            let result = list[0] *= 3;
+            return map[\"a\"] + list[0] + list[1] + result + evaluations;
+        ";
+        assert_eq!(run_balanced(source), Ok(Value::String("xy34231".into())));
+    }
+
+    #[test]
+    fn test_index_dispatches_to_user_methods() {
+        let source = "
+            class Grid {
+                init() { this.cells = {}; }
+                get(key) { return this.cells[key]; }
+                set(key, value) { this.cells[key] = value * 2; return nil; }
+            }
+            let grid = Grid();
+            let result = grid[\"a\"] = 21;
+            return grid[\"a\"] + result;
+        ";
+        assert_eq!(run(source), Ok(Value::Number(63.0)));
+    }
+
+    #[test]
+    fn test_index_errors() {
+        assert_eq!(run("return 1[0];").unwrap_err().message, "Cannot index non-object.");
+        assert_eq!(run("class A {} return A()[0];").unwrap_err().message, "Cannot index instance of 'A' without a 'get' method.");
+        assert_eq!(run("return {1: 2};").unwrap_err().message, "Map keys must be strings.");
+        assert_eq!(run("let list = [1]; list[1] = 2;").unwrap_err().message, "List index 1 out of range for length 1");
+        assert_eq!(run("return [1, 2][2];").unwrap_err().message, "List index 2 out of range for length 2");
+        assert_eq!(run("return [1, 2][-1];").unwrap_err().message, "List index -1 out of range for length 2");
+        assert_eq!(run("return [1, 2][0.5];").unwrap_err().message, "List index 0.5 is not an integer");
+        assert_eq!(run("let list = [1, 2]; list[1.5] = 3;").unwrap_err().message, "List index 1.5 is not an integer");
+    }
+
+    #[test]
+    fn test_bound_method_arity_leaves_out_receiver() {
+        let error = run("class A { m(x) { return x; } }\nlet f = A().m;\nf();").unwrap_err();
+        assert_eq!(error.message, "Expected 1 arguments but got 0.");
+        assert_eq!(run("class A { m(x) { return x; } } let f = A().m; return f(3);"), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn test_native_arity() {
+        let cases = [
+            ("fetch();", "Expected 1 arguments but got 0."),
+            ("readln(1);", "Expected 0 arguments but got 1."),
+            ("doc();", "Expected 1 arguments but got 0."),
+            ("[].add();", "Expected 1 arguments but got 0."),
+            ("List().get();", "Expected 1 arguments but got 0."),
+            ("[1].set(0);", "Expected 2 arguments but got 1."),
+            ("[].toString(1);", "Expected 0 arguments but got 1."),
+            ("[].iterator(1);", "Expected 0 arguments but got 1."),
+            ("[1].iterator().next(1);", "Expected 0 arguments but got 1."),
+            ("let m = {}; m.get();", "Expected 1 arguments but got 0."),
+            ("let m = {}; m.set(\"a\");", "Expected 2 arguments but got 1."),
+            ("let m = {}; m.toString(1);", "Expected 0 arguments but got 1."),
+            ("let m = {}; m.iterator(1);", "Expected 0 arguments but got 1."),
+            ("let add = [].add; add();", "Expected 1 arguments but got 0."),
+        ];
+        for (source, message) in cases {
+            assert_eq!(run(source).unwrap_err().message, message, "{}", source);
+        }
+        assert_eq!(run("return List(1, 2).get(1);"), Ok(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn test_native_error() {
+        let error = run("let map = Map(); map.get(1);").unwrap_err();
+        assert_eq!(error.message, "Second argument must be a string");
+    }
+}